@@ -4,15 +4,19 @@
 //! in the Agent Enforcer 2 blueprint.
 //!
 //! It is intended to be called by an orchestrator (e.g. `build.ps1`) and prints
-//! structured JSON output describing tool results.
+//! a JSON report that conforms to `schema/ci_report.schema.json`.
 //!
-//! Adapt the `CONFIG` section to your project.
+//! Adapt the `CONFIG` section to your project, or override it per project via
+//! `.ci/config.json` (tools, target dirs, per-stage settings).
 //!
 //! Dependencies (put into your project's `Cargo.toml` if you adopt this file):
 //! - clap = { version = "4", features = ["derive"] }
 //! - serde = { version = "1", features = ["derive"] }
 //! - serde_json = "1"
 //! - anyhow = "1"
+//! - chrono = { version = "0.4", default-features = false, features = ["clock", "std"] }
+//! - sha2 = "0.10"
+//! - rustc-demangle = "0.1"
 //!
 //! Security:
 //! - Never embed secrets in this file. Use environment variables instead.
@@ -20,16 +24,27 @@
 //!
 //! Notes:
 //! - This template avoids shell invocation and uses `std::process::Command`.
-//! - Add timeouts if your environment requires strict execution limits.
+//! - Every tool runs under a heartbeat watchdog (see `docs/en/HEARTBEAT.md`).
+//! - Every run rewrites `.enforcer/Enforcer_last_check.log` and appends to
+//!   `.enforcer/Enforcer_stats.log` (see `docs/en/REPORT_FORMAT.md`);
+//!   `build stats` summarizes the latter.
 #![forbid(unsafe_code)]
 
-use std::collections::BTreeMap;
-use std::process::{Command, ExitStatus};
-use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use serde::Serialize;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 
 // =============================================================================
 // Configuration
@@ -38,49 +53,286 @@ use serde::Serialize;
 /// Directories to check (relative to project root).
 const TARGET_DIRS: &[&str] = &["src", "crates", "tests"];
 
+/// Cache directory for hash guards and trust stamps (see `docs/en/CACHING.md`).
+const CACHE_DIR: &str = ".ci_cache";
+
+/// Standard stage names in execution order (see `docs/en/STAGES.md`).
+///
+/// * Tools run in this order; custom stages run after the standard ones.
+/// * Accepted by `--skip` even when no tool implements the stage.
+const STANDARD_STAGES: &[&str] = &[
+    "self-check",
+    "fmt",
+    "line-limits",
+    "lint",
+    "compile",
+    "build",
+    "test",
+    "coverage",
+    "e2e",
+    "security",
+    "launch",
+    "archive",
+];
+
+/// Position of `stage` in the standard order; custom stages sort last.
+fn stage_rank(stage: &str) -> usize {
+    STANDARD_STAGES
+        .iter()
+        .position(|s| *s == stage)
+        .unwrap_or(STANDARD_STAGES.len())
+}
+
+/// Orders the stages of `tools` so every stage runs after the stages it depends on.
+///
+/// * Among ready stages the standard order wins, then the name.
+/// * Dependencies on stages without a tool impose no order.
+/// * Fails on a `depends_on` cycle, naming the stages involved.
+fn stage_order(tools: &BTreeMap<String, ToolConfig>) -> Result<Vec<String>> {
+    let mut deps: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for cfg in tools.values() {
+        deps.entry(cfg.stage.as_str()).or_default();
+    }
+    for cfg in tools.values() {
+        for dep in &cfg.depends_on {
+            if deps.contains_key(dep.as_str()) {
+                deps.entry(cfg.stage.as_str()).or_default().insert(dep);
+            }
+        }
+    }
+
+    let mut order: Vec<String> = Vec::new();
+    while !deps.is_empty() {
+        let next = deps
+            .iter()
+            .filter(|(_, d)| d.is_empty())
+            .map(|(stage, _)| *stage)
+            .min_by_key(|stage| (stage_rank(stage), *stage));
+        let Some(next) = next else {
+            let cycle: Vec<&str> = deps.keys().copied().collect();
+            return Err(anyhow!(
+                "depends_on cycle between stages: {}",
+                cycle.join(", ")
+            ));
+        };
+        deps.remove(next);
+        for d in deps.values_mut() {
+            d.remove(next);
+        }
+        order.push(next.to_string());
+    }
+    Ok(order)
+}
+
+/// Schema report written by `orchestrate`.
+const REPORT_PATH: &str = ".ci_cache/report.json";
+
+/// Archived reports, `<YYYYMMDDTHHMMSSZ>_<commit>.json`; kept by `--clean`.
+const HISTORY_DIR: &str = ".ci_cache/history";
+
+/// Archived reports kept when `history.max_count` is not configured.
+const HISTORY_MAX_DEFAULT: usize = 50;
+
+/// Enforcer logs (gitignored; see `docs/en/REPORT_FORMAT.md`).
+const ENFORCER_DIR: &str = ".enforcer";
+
+/// Profile used when `--profile` is not given.
+const DEFAULT_PROFILE: &str = "full";
+
+/// Runs before everything else; any other stage waits for it and is skipped if it fails.
+const SELF_CHECK_STAGE: &str = "self-check";
+
+/// Config schema locations tried by `self-check` when the config has no local `$schema`.
+const CONFIG_SCHEMA_PATHS: &[&str] = &[".ci/config.schema.json", "schema/ci_config.schema.json"];
+
+/// Timeout for `--version` probes and CI script linters.
+const PROBE_TIMEOUT_SEC: u64 = 60;
+
+/// Directories (not recursive) whose `*.sh` / `*.ps1` scripts `self-check` lints.
+const CI_SCRIPT_DIRS: &[&str] = &[".", "tools", "tools/ci", ".ci"];
+
+/// Lock for tools that build into the cargo target dir; they would only block on each other.
+const CARGO_TARGET_LOCK: &str = "cargo-target";
+
+/// Directories never enumerated (hashing, line limits, CI scripts) at the project root;
+/// `target` is also skipped inside a crate and `.git` at any depth.
+const EXCLUDED_DIRS: &[&str] = &[".git", "target", ".ci_cache", ".enforcer"];
+
+/// `cargo-test` arguments used with `--nextest`; plain `cargo test` is the fallback.
+const NEXTEST_ARGS: &[&str] = &["nextest", "run", "--all-features"];
+
+/// Workspace manifests hashed for every tool (missing files are ignored).
+const HASH_MANIFESTS: &[&str] = &["Cargo.toml", "Cargo.lock"];
+
 /// Configures which tools/stages exist and how they are executed.
 ///
 /// * Keep this list aligned with your `build.ps1` stages.
-fn tools_config() -> BTreeMap<&'static str, ToolConfig> {
+fn tools_config() -> BTreeMap<String, ToolConfig> {
     BTreeMap::from([
         (
-            "cargo-fmt",
+            "self-check".to_string(),
             ToolConfig {
-                description: "Formatter (cargo fmt)",
+                stage: SELF_CHECK_STAGE.to_string(),
+                description: "CI self-check: config schema, commands, target dirs, CI scripts"
+                    .to_string(),
                 critical: true,
                 can_fix: false,
-                command: "cargo",
-                args: vec!["fmt", "--all", "--", "--check"],
-                args_fix: vec!["fmt", "--all"],
+                command: "".to_string(),
+                args: strings(&[]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&[]),
+                builtin: Some(Builtin::SelfCheck),
+                parser: OutputParser::Plain,
+                cache_inputs: strings(&[]),
+                depends_on: strings(&[]),
+                lock: None,
             },
         ),
         (
-            "cargo-clippy",
+            "cargo-fmt".to_string(),
             ToolConfig {
-                description: "Linter (cargo clippy)",
+                stage: "fmt".to_string(),
+                description: "Formatter (cargo fmt)".to_string(),
                 critical: true,
-                can_fix: false,
-                command: "cargo",
-                args: vec![
+                can_fix: true,
+                command: "cargo".to_string(),
+                args: strings(&["fmt", "--all", "--", "--check"]),
+                args_fix: strings(&["fmt", "--all"]),
+                fallback_args: strings(&[]),
+                builtin: None,
+                parser: OutputParser::Plain,
+                cache_inputs: strings(&["rustfmt.toml", ".rustfmt.toml"]),
+                depends_on: strings(&[]),
+                lock: None,
+            },
+        ),
+        (
+            "cargo-clippy".to_string(),
+            ToolConfig {
+                stage: "lint".to_string(),
+                description: "Linter (cargo clippy)".to_string(),
+                critical: true,
+                can_fix: true,
+                command: "cargo".to_string(),
+                args: strings(&[
                     "clippy",
                     "--all-targets",
                     "--all-features",
+                    "--message-format=json",
                     "--",
                     "-D",
                     "warnings",
-                ],
-                args_fix: vec![],
+                ]),
+                args_fix: strings(&[
+                    "clippy",
+                    "--fix",
+                    "--allow-dirty",
+                    "--allow-staged",
+                    "--all-targets",
+                    "--all-features",
+                ]),
+                fallback_args: strings(&[]),
+                builtin: None,
+                parser: OutputParser::CargoDiagnostics,
+                cache_inputs: strings(&["Cargo.toml", "Cargo.lock", "clippy.toml", ".clippy.toml"]),
+                depends_on: strings(&[]),
+                lock: Some(CARGO_TARGET_LOCK.to_string()),
+            },
+        ),
+        (
+            "cargo-check".to_string(),
+            ToolConfig {
+                stage: "compile".to_string(),
+                description: "Compile check (cargo check)".to_string(),
+                critical: true,
+                can_fix: false,
+                command: "cargo".to_string(),
+                args: strings(&[
+                    "check",
+                    "--all-targets",
+                    "--all-features",
+                    "--message-format=json",
+                ]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&[]),
+                builtin: None,
+                parser: OutputParser::CargoDiagnostics,
+                cache_inputs: strings(&["Cargo.toml", "Cargo.lock"]),
+                depends_on: strings(&[]),
+                lock: Some(CARGO_TARGET_LOCK.to_string()),
+            },
+        ),
+        (
+            "line-limits".to_string(),
+            ToolConfig {
+                stage: "line-limits".to_string(),
+                description: "Policy: executable lines per file, files per directory (built-in)"
+                    .to_string(),
+                critical: false,
+                can_fix: false,
+                command: "".to_string(),
+                args: strings(&[]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&[]),
+                builtin: Some(Builtin::LineLimits),
+                parser: OutputParser::Plain,
+                cache_inputs: strings(&[]),
+                depends_on: strings(&[]),
+                lock: None,
             },
         ),
         (
-            "cargo-test",
+            "cargo-test".to_string(),
             ToolConfig {
-                description: "Test runner (cargo test)",
+                stage: "test".to_string(),
+                description: "Test runner (cargo test)".to_string(),
                 critical: true,
                 can_fix: false,
-                command: "cargo",
-                args: vec!["test", "--all-features"],
-                args_fix: vec![],
+                command: "cargo".to_string(),
+                args: strings(&["test", "--all-features"]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&[]),
+                builtin: None,
+                parser: OutputParser::LibTest,
+                cache_inputs: strings(&["Cargo.toml", "Cargo.lock"]),
+                depends_on: strings(&["compile"]),
+                lock: Some(CARGO_TARGET_LOCK.to_string()),
+            },
+        ),
+        (
+            "cargo-coverage".to_string(),
+            ToolConfig {
+                stage: "coverage".to_string(),
+                description: "Coverage (cargo llvm-cov, tarpaulin fallback)".to_string(),
+                critical: false,
+                can_fix: false,
+                command: "cargo".to_string(),
+                args: strings(&["llvm-cov", "--workspace", "--all-features", "--json"]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&["tarpaulin", "--workspace", "--all-features"]),
+                builtin: None,
+                parser: OutputParser::Coverage,
+                cache_inputs: strings(&["Cargo.toml", "Cargo.lock"]),
+                depends_on: strings(&["test"]),
+                lock: Some(CARGO_TARGET_LOCK.to_string()),
+            },
+        ),
+        (
+            "cargo-audit".to_string(),
+            ToolConfig {
+                stage: "security".to_string(),
+                description: "Dependency advisories (cargo audit)".to_string(),
+                critical: false,
+                can_fix: false,
+                command: "cargo".to_string(),
+                args: strings(&["audit", "--json"]),
+                args_fix: strings(&[]),
+                fallback_args: strings(&[]),
+                builtin: None,
+                parser: OutputParser::Advisories,
+                cache_inputs: strings(&["Cargo.lock"]),
+                depends_on: strings(&[]),
+                lock: None,
             },
         ),
     ])
@@ -88,226 +340,8641 @@ fn tools_config() -> BTreeMap<&'static str, ToolConfig> {
 
 #[derive(Clone, Debug)]
 struct ToolConfig {
-    description: &'static str,
+    /// Stage name used in the schema report (e.g. `fmt`, `lint`, `test`).
+    stage: String,
+    description: String,
     critical: bool,
     can_fix: bool,
-    command: &'static str,
+    command: String,
     /// Arguments for "check" mode.
-    args: Vec<&'static str>,
+    args: Vec<String>,
     /// Arguments for "fix" mode (optional).
-    args_fix: Vec<&'static str>,
+    args_fix: Vec<String>,
+    /// Arguments retried when the cargo subcommand in `args` is not installed (optional).
+    fallback_args: Vec<String>,
+    /// Built-in check implemented in this file; `command`/`args` are ignored when set.
+    builtin: Option<Builtin>,
+    /// How tool output is turned into report issues/details.
+    parser: OutputParser,
+    /// Config/lock files hashed in addition to the target dirs (missing files are ignored).
+    cache_inputs: Vec<String>,
+    /// Stages that must not have failed for this tool to run (e.g. `test` needs `compile`).
+    depends_on: Vec<String>,
+    /// Tools sharing a lock never run concurrently (see `CARGO_TARGET_LOCK`).
+    lock: Option<String>,
 }
 
-// =============================================================================
-// Output format
-// =============================================================================
+/// Built-in profiles following the stage execution matrix in `docs/en/PROFILES.md`.
+///
+/// * `.ci/config.json` `profiles.<name>` replaces a built-in profile of the same name.
+fn builtin_profiles() -> BTreeMap<String, Profile> {
+    BTreeMap::from([
+        (
+            "fast".to_string(),
+            Profile {
+                stages: Some(strings(&[
+                    "self-check",
+                    "fmt",
+                    "line-limits",
+                    "lint",
+                    "compile",
+                    "build",
+                    "launch",
+                ])),
+                ..Profile::default()
+            },
+        ),
+        (
+            "full".to_string(),
+            Profile {
+                skip: strings(&["archive"]),
+                ..Profile::default()
+            },
+        ),
+        (
+            "maintenance".to_string(),
+            Profile {
+                stages: Some(strings(&["self-check", "security"])),
+                ..Profile::default()
+            },
+        ),
+        (
+            "release".to_string(),
+            Profile {
+                skip: strings(&["launch"]),
+                ..Profile::default()
+            },
+        ),
+    ])
+}
 
-#[derive(Debug, Serialize)]
-struct ToolResult {
-    tool: String,
-    description: String,
-    available: bool,
-    exit_code: i32,
-    stdout: String,
-    stderr: String,
-    critical: bool,
-    can_fix: bool,
-    fixed: bool,
-    duration_ms: u128,
+/// A named selection of stages (`--profile`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    /// Stages to run; `None` means every stage not listed in `skip`.
+    stages: Option<Vec<String>>,
+    #[serde(default)]
+    skip: Vec<String>,
+    /// Timeout for tools when neither `--timeout-sec` nor the stage sets one.
+    timeout_sec: Option<u64>,
+    /// Coverage fail threshold for this profile (warn is raised to at least this value).
+    coverage_threshold: Option<f64>,
 }
 
-#[derive(Debug, Serialize)]
-struct Summary {
-    total_tools_run: usize,
-    critical_failures: usize,
-    overall_status: String,
-    duration_ms: u128,
+impl Profile {
+    fn includes(&self, stage: &str) -> bool {
+        let listed = self
+            .stages
+            .as_ref()
+            .is_none_or(|stages| stages.iter().any(|s| s == stage));
+        listed && !self.skip.iter().any(|s| s == stage)
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct Report {
-    tools: BTreeMap<String, ToolResult>,
-    summary: Summary,
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| (*s).to_string()).collect()
 }
 
-// =============================================================================
-// CLI
-// =============================================================================
+/// Checks implemented natively instead of spawning a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Builtin {
+    LineLimits,
+    SelfCheck,
+}
 
-#[derive(Debug, Parser)]
-#[command(name = "build.rs", about = "Rust CI tool runner (template)")]
-struct Cli {
-    /// Run only one tool by name (e.g. cargo-fmt).
-    #[arg(long)]
-    tool: Option<String>,
+/// Structured output parsers available to tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputParser {
+    /// Raw stdout/stderr only.
+    Plain,
+    /// `cargo --message-format=json` compiler messages (clippy, check, build).
+    CargoDiagnostics,
+    /// libtest output (text or nightly `--format json`) and `cargo nextest` summaries.
+    #[serde(rename = "libtest")]
+    LibTest,
+    /// `cargo llvm-cov --json` export, or the `cargo tarpaulin` summary line.
+    Coverage,
+    /// `--json` output of another `build.<lang>` layer (`{tool: result, summary}` or `{tools, summary}`).
+    LegacyJson,
+    /// `cargo audit --json` report, or `cargo deny --format json check advisories` lines.
+    Advisories,
+}
 
-    /// Override target dirs (repeatable): --path src --path crates
-    #[arg(long = "path")]
-    paths: Vec<String>,
+/// Advisory severity, from the CVSS base score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    None,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
 
-    /// Enable auto-fix where possible (tool-dependent).
-    #[arg(long)]
-    fix: bool,
+/// An advisory accepted on purpose (`stages.security.ignore_advisories`).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdvisoryIgnore {
+    /// Advisory id or alias (`RUSTSEC-...`, `CVE-...`).
+    id: String,
+    /// Why it is acceptable; required so ignores can be reviewed.
+    reason: String,
+}
 
-    /// Print the report as JSON (recommended for orchestrators).
-    #[arg(long)]
-    json: bool,
+/// `security` policy (see `docs/en/STAGES.md`).
+#[derive(Clone, Debug)]
+struct SecurityPolicy {
+    /// Vulnerabilities at or above this severity fail the stage; others warn.
+    fail_severity: Severity,
+    /// Ignored advisory id -> reason.
+    ignore: BTreeMap<String, String>,
+}
 
-    /// Print extra logs to stderr.
-    #[arg(long, short)]
-    verbose: bool,
+/// Coverage thresholds in percent (see `docs/en/STAGES.md`).
+#[derive(Clone, Copy, Debug)]
+struct CoverageThresholds {
+    warn: f64,
+    fail: f64,
 }
 
-// =============================================================================
-// Tool runner
-// =============================================================================
+/// `line-limits` thresholds (see `docs/en/STAGES.md`).
+#[derive(Clone, Copy, Debug)]
+struct LineLimits {
+    warn_lines: usize,
+    fail_lines: usize,
+    max_files_per_dir_warn: usize,
+    max_files_per_dir_fail: usize,
+    /// Exclude `#[cfg(test)] mod ... { }` blocks from executable line counts.
+    skip_test_modules: bool,
+}
 
-fn status_to_exit_code(status: ExitStatus) -> i32 {
-    match status.code() {
-        Some(code) => code,
-        None => 1, // terminated by signal on Unix, or otherwise unknown
+impl Default for LineLimits {
+    fn default() -> Self {
+        LineLimits {
+            warn_lines: 1500,
+            fail_lines: 2500,
+            max_files_per_dir_warn: 20,
+            max_files_per_dir_fail: 50,
+            skip_test_modules: true,
+        }
     }
 }
 
-fn run_tool(
-    tool_name: &str,
-    cfg: &ToolConfig,
-    target_paths: &[String],
-    fix_mode: bool,
-    verbose: bool,
-) -> ToolResult {
-    let started = Instant::now();
+/// Per-stage policy knobs consumed by output parsers and built-in checks.
+#[derive(Clone, Debug)]
+struct StagePolicy {
+    coverage: CoverageThresholds,
+    line_limits: LineLimits,
+    /// Number of CovRank entries kept in the coverage stage `details`.
+    covrank_limit: usize,
+    /// Issue rules dropped from the report, per stage (`stages.<name>.disabled_rules`).
+    disabled_rules: BTreeMap<String, Vec<String>>,
+    security: SecurityPolicy,
+}
 
-    let mut cmd = Command::new(cfg.command);
+/// CovRank entries kept in `details` even when `--covrank` is not requested.
+const COVRANK_DETAILS_DEFAULT: usize = 20;
 
-    let args = if fix_mode && cfg.can_fix && !cfg.args_fix.is_empty() {
-        &cfg.args_fix
-    } else {
-        &cfg.args
-    };
-    cmd.args(args);
+/// Git history window used to measure churn for CovRank.
+const COVRANK_CHURN_SINCE: &str = "90 days ago";
 
-    // * Rust tooling typically uses the workspace config; paths are optional.
-    // * If you want per-path clippy checks, adapt this logic to your layout.
-    if verbose {
-        eprintln!("Running: {} {}", cfg.command, args.join(" "));
-        if !target_paths.is_empty() {
-            eprintln!("Target paths: {}", target_paths.join(", "));
-        }
-    }
+/// Full tool logs (e.g. `line-limits` details) are written here.
+const LOG_DIR: &str = ".ci_cache/logs";
 
-    let output = match cmd.output() {
-        Ok(out) => out,
-        Err(err) => {
-            return ToolResult {
-                tool: tool_name.to_string(),
-                description: cfg.description.to_string(),
-                available: false,
-                exit_code: 127,
-                stdout: String::new(),
-                stderr: format!("Failed to execute `{}`: {}", cfg.command, err),
-                critical: cfg.critical,
-                can_fix: cfg.can_fix,
-                fixed: fix_mode && cfg.can_fix,
-                duration_ms: started.elapsed().as_millis(),
-            };
-        }
-    };
+/// Tool logs kept when `logs.max_count` is not configured (about 20 full runs).
+const LOGS_MAX_DEFAULT: usize = 200;
 
-    ToolResult {
-        tool: tool_name.to_string(),
-        description: cfg.description.to_string(),
-        available: true,
-        exit_code: status_to_exit_code(output.status),
-        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-        critical: cfg.critical,
-        can_fix: cfg.can_fix,
-        fixed: fix_mode && cfg.can_fix,
-        duration_ms: started.elapsed().as_millis(),
-    }
-}
+/// Offenders printed to the console; the full list goes to the log file.
+const TOP_OFFENDERS: usize = 5;
 
-fn run_all_checks(cli: &Cli) -> Result<Report> {
-    let started = Instant::now();
+// =============================================================================
+// External config (.ci/config.json)
+// =============================================================================
 
-    let configs = tools_config();
+/// Optional project config; defaults above apply when it is absent.
+const CONFIG_PATH: &str = ".ci/config.json";
 
-    let mut tools_to_run: Vec<String> = if let Some(ref only) = cli.tool {
-        vec![only.clone()]
-    } else {
-        configs.keys().map(|s| (*s).to_string()).collect()
-    };
+/// On-disk shape of `.ci/config.json` (see `docs/en/STAGES.md`, `docs/en/PROFILES.md`).
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// Schema reference for editors; `self-check` validates against it.
+    #[serde(rename = "$schema", default)]
+    _schema: Option<String>,
+    target_dirs: Option<Vec<String>>,
+    /// Tool overrides keyed by tool name; unknown names define new tools.
+    #[serde(default)]
+    tools: BTreeMap<String, ToolOverride>,
+    #[serde(default)]
+    stages: BTreeMap<String, StageSettings>,
+    /// Named profiles for `--profile`; built-in names are replaced, not merged.
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    /// Other `build.<lang>` layers run by `orchestrate`.
+    #[serde(default)]
+    layers: BTreeMap<String, LayerConfig>,
+    #[serde(default)]
+    history: HistorySettings,
+    #[serde(default)]
+    logs: LogSettings,
+    #[serde(default)]
+    toolchain: ToolchainSettings,
+    #[serde(default)]
+    files: FileSettings,
+}
 
-    // Standard order.
-    let preferred_order = ["cargo-fmt", "cargo-clippy", "cargo-test"];
-    tools_to_run.sort_by_key(|name| {
-        preferred_order
-            .iter()
-            .position(|x| x == name)
-            .unwrap_or(999)
-    });
+/// File discovery for hashing, line limits and CI scripts (`files`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSettings {
+    /// Also list untracked files that are not ignored (default `true`).
+    include_untracked: Option<bool>,
+}
 
-    let target_paths = if cli.paths.is_empty() {
-        TARGET_DIRS.iter().map(|p| (*p).to_string()).collect()
-    } else {
-        cli.paths.clone()
-    };
+/// Tool version pinning checked by the preflight (`toolchain`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolchainSettings {
+    /// Required Rust channel: `stable`, `beta` or `nightly`.
+    channel: Option<String>,
+    /// Minimum versions keyed by probe name (`cargo`, `cargo-llvm-cov`, `python3`, ...).
+    #[serde(default)]
+    min_versions: BTreeMap<String, String>,
+}
+
+/// Report archive (`history`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HistorySettings {
+    /// Reports kept in `HISTORY_DIR`; `0` disables the archive.
+    max_count: Option<usize>,
+}
 
-    let mut results: BTreeMap<String, ToolResult> = BTreeMap::new();
+/// Tool log retention (`logs`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSettings {
+    /// Newest logs kept in `LOG_DIR`; `0` keeps all of them.
+    max_count: Option<usize>,
+}
 
-    for tool_name in tools_to_run {
-        let cfg = configs
-            .get(tool_name.as_str())
-            .ok_or_else(|| anyhow!("Unknown tool: {}", tool_name))?;
+/// Another language layer (e.g. `build.py`, `build.ts`) invoked once per tool with `--tool`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConfig {
+    command: String,
+    /// Arguments before `--tool <name>`; should make the layer print JSON (`--json`).
+    #[serde(default)]
+    args: Vec<String>,
+    /// Layer tool name -> stage name, e.g. `"ruff-lint": "lint"`.
+    tools: BTreeMap<String, String>,
+    #[serde(default = "default_true")]
+    critical: bool,
+    /// Files/dirs hashed for the cache (without them the layer always runs).
+    #[serde(default)]
+    cache_inputs: Vec<String>,
+}
 
-        let res = run_tool(&tool_name, cfg, &target_paths, cli.fix, cli.verbose);
-        results.insert(tool_name, res);
-    }
+fn default_true() -> bool {
+    true
+}
 
-    let critical_failures = results
-        .values()
-        .filter(|r| r.critical && r.exit_code != 0)
-        .count();
+/// Partial tool definition; unset fields keep the built-in default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolOverride {
+    /// `false` removes the tool entirely.
+    enabled: Option<bool>,
+    stage: Option<String>,
+    description: Option<String>,
+    critical: Option<bool>,
+    can_fix: Option<bool>,
+    command: Option<String>,
+    args: Option<Vec<String>>,
+    args_fix: Option<Vec<String>>,
+    fallback_args: Option<Vec<String>>,
+    builtin: Option<Builtin>,
+    parser: Option<OutputParser>,
+    cache_inputs: Option<Vec<String>>,
+    depends_on: Option<Vec<String>>,
+    lock: Option<String>,
+}
 
-    let overall_status = if critical_failures > 0 {
-        "FAIL".to_string()
-    } else {
-        "PASS".to_string()
-    };
+/// Per-stage settings (`stages.<name>`); keys apply to the stages that understand them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StageSettings {
+    /// `coverage`: percent below which the stage warns.
+    warn_threshold: Option<f64>,
+    /// `coverage`: percent below which the stage fails.
+    fail_threshold: Option<f64>,
+    /// `line-limits`: executable lines per file.
+    warn_threshold_lines: Option<usize>,
+    fail_threshold_lines: Option<usize>,
+    /// `line-limits`: files per directory.
+    max_files_per_dir_warn: Option<usize>,
+    max_files_per_dir_fail: Option<usize>,
+    skip_test_modules: Option<bool>,
+    /// Rules dropped from the report; clippy lints are also passed as `-A <rule>`.
+    #[serde(default)]
+    disabled_rules: Vec<String>,
+    /// Timeout for tools of this stage when `--timeout-sec` is not given.
+    timeout_sec: Option<u64>,
+    /// `security`: accepted advisories, each with a `reason`.
+    #[serde(default)]
+    ignore_advisories: Vec<AdvisoryIgnore>,
+    /// `security`: local advisory-db checkout; no network fetch when set.
+    advisory_db: Option<String>,
+    /// `security`: lowest vulnerability severity that fails (default `high`).
+    fail_severity: Option<Severity>,
+}
 
-    Ok(Report {
-        summary: Summary {
-            total_tools_run: results.len(),
-            critical_failures,
-            overall_status,
-            duration_ms: started.elapsed().as_millis(),
-        },
-        tools: results,
-    })
+/// Effective configuration: built-in defaults merged with `.ci/config.json`.
+#[derive(Debug)]
+struct Settings {
+    target_dirs: Vec<String>,
+    tools: BTreeMap<String, ToolConfig>,
+    stages: BTreeMap<String, StageSettings>,
+    profiles: BTreeMap<String, Profile>,
+    layers: BTreeMap<String, LayerConfig>,
+    /// Archived reports to keep (`history.max_count`).
+    history_max: usize,
+    /// Tool logs to keep (`logs.max_count`, `0` = all).
+    logs_max: usize,
+    toolchain: ToolchainSettings,
+    /// `files.include_untracked`.
+    include_untracked: bool,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+impl Settings {
+    fn stage(&self, name: &str) -> StageSettings {
+        self.stages.get(name).cloned().unwrap_or_default()
+    }
 
-    let report = run_all_checks(&cli).context("Failed to run Rust checks")?;
+    fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            anyhow!("Unknown profile: {name} (known: {})", known.join(", "))
+        })
+    }
 
-    if cli.json {
-        let json = serde_json::to_string_pretty(&report)?;
-        println!("{json}");
-    } else {
-        eprintln!("Status: {}", report.summary.overall_status);
-        eprintln!("Duration: {}ms", report.summary.duration_ms);
-        for (name, r) in &report.tools {
-            let status = if r.exit_code == 0 { "OK" } else { "FAIL" };
-            println!("  {name}: {status}");
+    /// Stage names accepted by `--skip`: standard stages plus those of configured tools.
+    fn known_stages(&self) -> BTreeSet<String> {
+        STANDARD_STAGES
+            .iter()
+            .map(|s| (*s).to_string())
+            .chain(self.tools.values().map(|cfg| cfg.stage.clone()))
+            .chain(self.layers.values().flat_map(|l| l.tools.values().cloned()))
+            .collect()
+    }
+
+    /// Expands `layers` into tools named `<layer>-<tool>`.
+    fn layer_tools(&self) -> BTreeMap<String, ToolConfig> {
+        let mut tools = BTreeMap::new();
+        for (layer_name, layer) in &self.layers {
+            for (tool, stage) in &layer.tools {
+                let mut args = layer.args.clone();
+                args.push("--tool".to_string());
+                args.push(tool.clone());
+                tools.insert(
+                    format!("{layer_name}-{tool}"),
+                    ToolConfig {
+                        stage: stage.clone(),
+                        description: format!("{tool} ({layer_name} layer)"),
+                        critical: layer.critical,
+                        can_fix: false,
+                        command: layer.command.clone(),
+                        args,
+                        args_fix: Vec::new(),
+                        fallback_args: Vec::new(),
+                        builtin: None,
+                        parser: OutputParser::LegacyJson,
+                        cache_inputs: layer.cache_inputs.clone(),
+                        depends_on: Vec::new(),
+                        lock: None,
+                    },
+                );
+            }
         }
+        tools
     }
 
-    if report.summary.overall_status == "PASS" {
-        Ok(())
-    } else {
-        Err(anyhow!("Rust checks failed"))
+    /// Builds the stage policy; CLI flags win over config values, which win over defaults.
+    fn policy(&self, cli: &Cli, profile: &Profile) -> StagePolicy {
+        let coverage = self.stage("coverage");
+        let limits = self.stage("line-limits");
+        let security = self.stage("security");
+        let defaults = LineLimits::default();
+        let fail = cli
+            .coverage_fail
+            .or(profile.coverage_threshold)
+            .or(coverage.fail_threshold)
+            .unwrap_or(60.0);
+        let warn = cli
+            .coverage_warn
+            .or(coverage.warn_threshold)
+            .unwrap_or(75.0);
+        StagePolicy {
+            coverage: CoverageThresholds {
+                warn: if profile.coverage_threshold.is_some() {
+                    warn.max(fail)
+                } else {
+                    warn
+                },
+                fail,
+            },
+            line_limits: LineLimits {
+                warn_lines: limits.warn_threshold_lines.unwrap_or(defaults.warn_lines),
+                fail_lines: limits.fail_threshold_lines.unwrap_or(defaults.fail_lines),
+                max_files_per_dir_warn: limits
+                    .max_files_per_dir_warn
+                    .unwrap_or(defaults.max_files_per_dir_warn),
+                max_files_per_dir_fail: limits
+                    .max_files_per_dir_fail
+                    .unwrap_or(defaults.max_files_per_dir_fail),
+                skip_test_modules: limits
+                    .skip_test_modules
+                    .unwrap_or(defaults.skip_test_modules),
+            },
+            covrank_limit: cli.covrank.max(COVRANK_DETAILS_DEFAULT),
+            disabled_rules: self
+                .stages
+                .iter()
+                .filter(|(_, st)| !st.disabled_rules.is_empty())
+                .map(|(name, st)| (name.clone(), st.disabled_rules.clone()))
+                .collect(),
+            security: SecurityPolicy {
+                fail_severity: security.fail_severity.unwrap_or(Severity::High),
+                ignore: security
+                    .ignore_advisories
+                    .iter()
+                    .map(|i| (i.id.clone(), i.reason.clone()))
+                    .collect(),
+            },
+        }
+    }
+}
+
+fn apply_override(name: &str, base: Option<ToolConfig>, o: ToolOverride) -> Result<ToolConfig> {
+    let mut cfg = match base {
+        Some(cfg) => cfg,
+        None => {
+            let stage = o
+                .stage
+                .clone()
+                .ok_or_else(|| anyhow!("tools.{name}: new tool requires `stage`"))?;
+            if o.command.is_none() && o.builtin.is_none() {
+                return Err(anyhow!(
+                    "tools.{name}: new tool requires `command` (or `builtin`)"
+                ));
+            }
+            ToolConfig {
+                stage,
+                description: name.to_string(),
+                critical: true,
+                can_fix: false,
+                command: String::new(),
+                args: Vec::new(),
+                args_fix: Vec::new(),
+                fallback_args: Vec::new(),
+                builtin: None,
+                parser: OutputParser::Plain,
+                cache_inputs: Vec::new(),
+                depends_on: Vec::new(),
+                lock: None,
+            }
+        }
+    };
+
+    if let Some(v) = o.stage {
+        cfg.stage = v;
+    }
+    if let Some(v) = o.description {
+        cfg.description = v;
+    }
+    if let Some(v) = o.critical {
+        cfg.critical = v;
+    }
+    if let Some(v) = o.can_fix {
+        cfg.can_fix = v;
+    }
+    if let Some(v) = o.command {
+        cfg.command = v;
+    }
+    if let Some(v) = o.args {
+        cfg.args = v;
+    }
+    if let Some(v) = o.args_fix {
+        cfg.args_fix = v;
     }
+    if let Some(v) = o.fallback_args {
+        cfg.fallback_args = v;
+    }
+    if o.builtin.is_some() {
+        cfg.builtin = o.builtin;
+    }
+    if let Some(v) = o.parser {
+        cfg.parser = v;
+    }
+    if let Some(v) = o.cache_inputs {
+        cfg.cache_inputs = v;
+    }
+    if let Some(v) = o.depends_on {
+        cfg.depends_on = v;
+    }
+    if o.lock.is_some() {
+        cfg.lock = o.lock;
+    }
+    Ok(cfg)
 }
 
+/// Rejects configs that would only fail later (or silently do nothing).
+fn validate_settings(settings: &Settings) -> Result<()> {
+    let mut errors: Vec<String> = Vec::new();
+    let known_stages = settings.known_stages();
+
+    if settings.target_dirs.is_empty() {
+        errors.push("target_dirs: must not be empty".to_string());
+    }
+    for (name, cfg) in &settings.tools {
+        if cfg.stage.trim().is_empty() {
+            errors.push(format!("tools.{name}.stage: must not be empty"));
+        }
+        if cfg.builtin.is_none() && cfg.command.trim().is_empty() {
+            errors.push(format!("tools.{name}.command: must not be empty"));
+        }
+        for dep in cfg.depends_on.iter().filter(|d| !known_stages.contains(*d)) {
+            errors.push(format!("tools.{name}.depends_on: unknown stage `{dep}`"));
+        }
+    }
+    if let Err(err) = stage_order(&settings.tools) {
+        errors.push(format!("tools: {err}"));
+    }
+    for (name, st) in &settings.stages {
+        for (key, value) in [
+            ("warn_threshold", st.warn_threshold),
+            ("fail_threshold", st.fail_threshold),
+        ] {
+            if value.is_some_and(|v| !(0.0..=100.0).contains(&v)) {
+                errors.push(format!("stages.{name}.{key}: must be within 0..=100"));
+            }
+        }
+        if let (Some(warn), Some(fail)) = (st.warn_threshold, st.fail_threshold) {
+            if fail > warn {
+                errors.push(format!(
+                    "stages.{name}: fail_threshold ({fail}) must not exceed warn_threshold ({warn})"
+                ));
+            }
+        }
+        if let (Some(warn), Some(fail)) = (st.warn_threshold_lines, st.fail_threshold_lines) {
+            if warn > fail {
+                errors.push(format!(
+                    "stages.{name}: warn_threshold_lines ({warn}) must not exceed fail_threshold_lines ({fail})"
+                ));
+            }
+        }
+        for ignore in &st.ignore_advisories {
+            if ignore.id.trim().is_empty() {
+                errors.push(format!(
+                    "stages.{name}.ignore_advisories: `id` must not be empty"
+                ));
+            } else if ignore.reason.trim().is_empty() {
+                errors.push(format!(
+                    "stages.{name}.ignore_advisories: `{}` needs a `reason`",
+                    ignore.id
+                ));
+            }
+        }
+        if let Some(ref db) = st.advisory_db {
+            if !Path::new(db).is_dir() {
+                errors.push(format!(
+                    "stages.{name}.advisory_db: `{db}` is not a directory"
+                ));
+            }
+        }
+        if let (Some(warn), Some(fail)) = (st.max_files_per_dir_warn, st.max_files_per_dir_fail) {
+            if warn > fail {
+                errors.push(format!(
+                    "stages.{name}: max_files_per_dir_warn ({warn}) must not exceed max_files_per_dir_fail ({fail})"
+                ));
+            }
+        }
+    }
+
+    for (name, layer) in &settings.layers {
+        if layer.command.trim().is_empty() {
+            errors.push(format!("layers.{name}.command: must not be empty"));
+        }
+        if layer.tools.is_empty() {
+            errors.push(format!("layers.{name}.tools: must not be empty"));
+        }
+        if settings
+            .tools
+            .keys()
+            .any(|t| t.starts_with(&format!("{name}-")))
+        {
+            errors.push(format!(
+                "layers.{name}: tool names `{name}-*` clash with configured tools"
+            ));
+        }
+    }
+    if let Some(ref channel) = settings.toolchain.channel {
+        if !["stable", "beta", "nightly"].contains(&channel.as_str()) {
+            errors.push(format!(
+                "toolchain.channel: `{channel}` is not stable, beta or nightly"
+            ));
+        }
+    }
+    for (name, version) in &settings.toolchain.min_versions {
+        if parse_version(version).is_none() {
+            errors.push(format!(
+                "toolchain.min_versions.{name}: `{version}` is not a version"
+            ));
+        }
+    }
+    for (name, profile) in &settings.profiles {
+        if profile.stages.as_ref().is_some_and(Vec::is_empty) {
+            errors.push(format!("profiles.{name}.stages: must not be empty"));
+        }
+        if profile
+            .coverage_threshold
+            .is_some_and(|v| !(0.0..=100.0).contains(&v))
+        {
+            errors.push(format!(
+                "profiles.{name}.coverage_threshold: must be within 0..=100"
+            ));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid CI config:\n  - {}", errors.join("\n  - ")))
+    }
+}
+
+/// Built-in defaults, used as-is without a config file.
+fn default_settings() -> Settings {
+    Settings {
+        target_dirs: strings(TARGET_DIRS),
+        tools: tools_config(),
+        stages: BTreeMap::new(),
+        profiles: builtin_profiles(),
+        layers: BTreeMap::new(),
+        history_max: HISTORY_MAX_DEFAULT,
+        logs_max: LOGS_MAX_DEFAULT,
+        toolchain: ToolchainSettings::default(),
+        include_untracked: true,
+    }
+}
+
+/// Loads `path` (or `CONFIG_PATH`) and merges it over the built-in defaults.
+///
+/// * A missing default config is fine; a missing explicit `--config` is an error.
+/// * The config file is added to every tool's cache inputs.
+fn load_settings(path: Option<&Path>) -> Result<Settings> {
+    let explicit = path.is_some();
+    let path = path.unwrap_or(Path::new(CONFIG_PATH));
+
+    let file: Option<ConfigFile> = if path.is_file() {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Some(
+            serde_json::from_str(&text)
+                .with_context(|| format!("Invalid CI config {}", path.display()))?,
+        )
+    } else if explicit {
+        return Err(anyhow!("Config file not found: {}", path.display()));
+    } else {
+        None
+    };
+
+    let mut settings = default_settings();
+
+    if let Some(file) = file {
+        if let Some(dirs) = file.target_dirs {
+            settings.target_dirs = dirs;
+        }
+        for (name, o) in file.tools {
+            if o.enabled == Some(false) {
+                settings.tools.remove(&name);
+                continue;
+            }
+            let cfg = apply_override(&name, settings.tools.remove(&name), o)
+                .with_context(|| format!("Invalid CI config {}", path.display()))?;
+            settings.tools.insert(name, cfg);
+        }
+        settings.stages = file.stages;
+        settings.profiles.extend(file.profiles);
+        settings.layers = file.layers;
+        settings.history_max = file.history.max_count.unwrap_or(HISTORY_MAX_DEFAULT);
+        settings.logs_max = file.logs.max_count.unwrap_or(LOGS_MAX_DEFAULT);
+        settings.toolchain = file.toolchain;
+        settings.include_untracked = file.files.include_untracked.unwrap_or(true);
+
+        let config_input = path.to_string_lossy().to_string();
+        for cfg in settings.tools.values_mut() {
+            cfg.cache_inputs.push(config_input.clone());
+        }
+    }
+
+    // * Clippy lints listed in `disabled_rules` are allowed on the command line as well.
+    for cfg in settings.tools.values_mut() {
+        let disabled = settings
+            .stages
+            .get(&cfg.stage)
+            .map(|st: &StageSettings| st.disabled_rules.as_slice())
+            .unwrap_or_default();
+        if cfg.parser == OutputParser::CargoDiagnostics && cfg.args.iter().any(|a| a == "--") {
+            for rule in disabled {
+                cfg.args.push("-A".to_string());
+                cfg.args.push(rule.clone());
+            }
+        }
+    }
+
+    // * A local advisory-db replaces the network fetch and becomes a cache input.
+    if let Some(db) = settings
+        .stages
+        .get("security")
+        .and_then(|st| st.advisory_db.clone())
+    {
+        for cfg in settings.tools.values_mut() {
+            if cfg.parser != OutputParser::Advisories {
+                continue;
+            }
+            match advisory_tool(cfg) {
+                // * cargo-deny reads `db-path` from deny.toml.
+                "deny" => cfg.args.push("--disable-fetch".to_string()),
+                _ => cfg
+                    .args
+                    .extend(["--db".to_string(), db.clone(), "--no-fetch".to_string()]),
+            }
+            cfg.cache_inputs.push(db.clone());
+        }
+    }
+
+    validate_settings(&settings).with_context(|| format!("Config: {}", path.display()))?;
+    Ok(settings)
+}
+
+// =============================================================================
+// Output format
+// =============================================================================
+
+/// Report schema version (see `schema/ci_report.schema.json`).
+const SCHEMA_VERSION: u32 = 1;
+
+/// Raw result of a single tool invocation.
+///
+/// * This is the internal record; it is converted into a schema `stage` for the
+///   report, or emitted as-is with `--legacy-json`.
+#[derive(Debug, Default, Serialize)]
+struct ToolResult {
+    tool: String,
+    description: String,
+    /// Command line actually run (empty for built-in checks).
+    command: String,
+    available: bool,
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    critical: bool,
+    can_fix: bool,
+    fixed: bool,
+    /// Killed by the watchdog after repeating the same output line.
+    hung: bool,
+    /// Killed by the watchdog after exceeding `--timeout-sec`.
+    timed_out: bool,
+    duration_ms: u128,
+    /// Full, unclamped output (`.ci_cache/logs/<tool>_<timestamp>.log`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_path: Option<String>,
+    /// Files rewritten by the `--fix` run that preceded this check.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fixed_files: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StageStatus {
+    Ok,
+    Warn,
+    Fail,
+    Cached,
+    Skip,
+}
+
+impl StageStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            StageStatus::Ok => "ok",
+            StageStatus::Warn => "warn",
+            StageStatus::Fail => "fail",
+            StageStatus::Cached => "cached",
+            StageStatus::Skip => "skip",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StageResult {
+    name: String,
+    status: StageStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+    duration_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct Issue {
+    language: String,
+    tool: String,
+    rule: String,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+struct TestCounts {
+    total: u64,
+    passed: u64,
+    failed: u64,
+    skipped: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+struct CoverageMetrics {
+    lines_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    functions_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branches_percent: Option<f64>,
+    status: StageStatus,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Metrics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<CoverageMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test_counts: Option<TestCounts>,
+}
+
+/// CI report in the shape of `schema/ci_report.schema.json`.
+#[derive(Debug, Serialize)]
+struct Report {
+    schema_version: u32,
+    started_at_utc: String,
+    finished_at_utc: String,
+    duration_ms: u128,
+    /// Overall status: `ok`, `warn` or `fail` (never `cached`/`skip`).
+    status: StageStatus,
+    stages: Vec<StageResult>,
+    issues: Vec<Issue>,
+    metrics: Metrics,
+    /// Versions found by the tool preflight, keyed by probe name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tool_versions: BTreeMap<String, String>,
+    /// Set for partial runs (`--changed-since` / `--staged`); absent means a full run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<RunScope>,
+}
+
+#[derive(Debug, Serialize)]
+struct LegacySummary {
+    total_tools_run: usize,
+    critical_failures: usize,
+    overall_status: String,
+    duration_ms: u128,
+}
+
+/// Pre-schema output shape (`{tools, summary}`), kept for `--legacy-json`.
+#[derive(Debug, Serialize)]
+struct LegacyReport {
+    tools: BTreeMap<String, ToolResult>,
+    summary: LegacySummary,
+}
+
+/// Everything collected during one run; rendered as `Report` or `LegacyReport`.
+#[derive(Debug)]
+struct RunOutcome {
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    duration_ms: u128,
+    results: Vec<ToolResult>,
+    stages: Vec<StageResult>,
+    issues: Vec<Issue>,
+    metrics: Metrics,
+    /// `history.max_count` of the settings the run used.
+    history_max: usize,
+    tool_versions: BTreeMap<String, String>,
+    scope: Option<RunScope>,
+}
+
+fn format_utc(ts: DateTime<Utc>) -> String {
+    ts.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Log file suffix; milliseconds keep two runs within one second apart.
+fn log_timestamp() -> String {
+    Utc::now().format("%Y%m%d_%H%M%S_%3f").to_string()
+}
+
+/// Maps stage statuses to the overall run status (`fail` > `warn` > `ok`).
+fn overall_status(stages: &[StageResult]) -> StageStatus {
+    if stages.iter().any(|s| s.status == StageStatus::Fail) {
+        StageStatus::Fail
+    } else if stages.iter().any(|s| s.status == StageStatus::Warn) {
+        StageStatus::Warn
+    } else {
+        StageStatus::Ok
+    }
+}
+
+fn stage_from_result(cfg: &ToolConfig, res: &ToolResult, parsed: &ParsedOutput) -> StageResult {
+    let critical_status = if cfg.critical {
+        StageStatus::Fail
+    } else {
+        StageStatus::Warn
+    };
+    let (status, note) = if !res.available {
+        (
+            critical_status,
+            Some(format!("`{}` is not available", cfg.command)),
+        )
+    } else if res.hung {
+        (critical_status, Some("Process hung".to_string()))
+    } else if res.timed_out {
+        (critical_status, Some("Timed out".to_string()))
+    } else if let (OutputParser::Advisories, Some(status)) = (cfg.parser, parsed.status) {
+        // * Advisory tools exit nonzero on any finding; severity decides instead.
+        (status, parsed.note.clone())
+    } else if let (0, Some(status)) = (res.exit_code, parsed.status) {
+        (status, parsed.note.clone())
+    } else if res.exit_code == 0 && parsed.warnings == 0 {
+        (StageStatus::Ok, parsed.note.clone())
+    } else if res.exit_code == 0 {
+        (
+            StageStatus::Warn,
+            parsed
+                .note
+                .clone()
+                .or_else(|| Some(format!("{} warnings (non-critical)", parsed.warnings))),
+        )
+    } else if cfg.critical {
+        (
+            StageStatus::Fail,
+            parsed
+                .note
+                .clone()
+                .or_else(|| Some(format!("Exit code: {}", res.exit_code))),
+        )
+    } else {
+        (
+            StageStatus::Warn,
+            Some(format!("Exit code: {} (non-critical)", res.exit_code)),
+        )
+    };
+
+    let mut details = json!({
+        "tool": res.tool,
+        "description": res.description,
+        "exit_code": res.exit_code,
+        "fixed": res.fixed,
+        "hung": res.hung,
+        "timed_out": res.timed_out,
+        "stdout": res.stdout,
+        "stderr": res.stderr,
+    });
+    let note = if res.fixed_files.is_empty() {
+        note
+    } else {
+        let fixed = format!("fixed {} file(s)", res.fixed_files.len());
+        Some(note.map_or(fixed.clone(), |n| format!("{n}; {fixed}")))
+    };
+
+    if let Some(obj) = details.as_object_mut() {
+        if let Some(ref log_path) = res.log_path {
+            obj.insert("log_path".to_string(), json!(log_path));
+        }
+        if !res.fixed_files.is_empty() {
+            obj.insert("fixed_files".to_string(), json!(res.fixed_files));
+        }
+        obj.extend(parsed.details.clone());
+    }
+
+    StageResult {
+        name: cfg.stage.clone(),
+        status,
+        note,
+        details: Some(details),
+        duration_ms: res.duration_ms,
+    }
+}
+
+impl RunOutcome {
+    fn status(&self) -> StageStatus {
+        overall_status(&self.stages)
+    }
+
+    /// Splits the outcome into the schema report and the pre-schema `{tools, summary}` shape.
+    fn into_reports(self) -> (Report, LegacyReport) {
+        let critical_failures = self
+            .results
+            .iter()
+            .filter(|r| r.critical && r.exit_code != 0)
+            .count();
+        let legacy_status = if critical_failures > 0 {
+            "FAIL".to_string()
+        } else {
+            "PASS".to_string()
+        };
+
+        let legacy = LegacyReport {
+            summary: LegacySummary {
+                total_tools_run: self.results.len(),
+                critical_failures,
+                overall_status: legacy_status,
+                duration_ms: self.duration_ms,
+            },
+            tools: self
+                .results
+                .into_iter()
+                .map(|r| (r.tool.clone(), r))
+                .collect(),
+        };
+        let report = Report {
+            schema_version: SCHEMA_VERSION,
+            started_at_utc: format_utc(self.started_at),
+            finished_at_utc: format_utc(self.finished_at),
+            duration_ms: self.duration_ms,
+            status: overall_status(&self.stages),
+            stages: self.stages,
+            issues: self.issues,
+            metrics: self.metrics,
+            tool_versions: self.tool_versions,
+            scope: self.scope,
+        };
+        (report, legacy)
+    }
+}
+
+// =============================================================================
+// CLI
+// =============================================================================
+
+#[derive(Debug, Parser)]
+#[command(name = "build.rs", about = "Rust CI tool runner (template)")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// Run only one tool by name (e.g. cargo-fmt).
+    #[arg(long, global = true)]
+    tool: Option<String>,
+
+    /// Override target dirs (repeatable): --path src --path crates; cargo fmt/clippy/check/test
+    /// then run with `-p` for the workspace members these paths touch.
+    #[arg(long = "path", global = true)]
+    paths: Vec<String>,
+
+    /// Check only files changed since the merge base with REF (plus untracked files);
+    /// clippy/check/test run on the affected crates and their reverse dependencies.
+    #[arg(long, global = true, value_name = "REF", conflicts_with = "staged")]
+    changed_since: Option<String>,
+
+    /// Like `--changed-since`, but for the staged files only (pre-commit).
+    #[arg(long, global = true)]
+    staged: bool,
+
+    /// Apply fixes (`cargo fmt`, `cargo clippy --fix`), list changed files, then re-check.
+    #[arg(long, global = true)]
+    fix: bool,
+
+    /// Print the report as JSON (recommended for orchestrators).
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Print the pre-schema `{tools, summary}` JSON instead of the schema report.
+    #[arg(long, global = true)]
+    legacy_json: bool,
+
+    /// Print extra logs to stderr.
+    #[arg(long, short, global = true)]
+    verbose: bool,
+
+    /// Run the test stage with `cargo nextest run` instead of `cargo test`.
+    #[arg(long)]
+    nextest: bool,
+
+    /// Ignore cache for this run (still writes new cache on success).
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Force all tools to re-run (implies --no-cache).
+    #[arg(long, global = true)]
+    force_all: bool,
+
+    /// Delete the cache directory before running.
+    #[arg(long, global = true)]
+    clean: bool,
+
+    /// Profile selecting stages: fast, full, maintenance, release, or one from the config.
+    #[arg(long, default_value = DEFAULT_PROFILE, global = true)]
+    profile: String,
+
+    /// Max chars of stdout/stderr kept per tool in the report (0 = unlimited);
+    /// the full output is always in `.ci_cache/logs/`.
+    #[arg(long, default_value_t = 4000, global = true)]
+    clamp: usize,
+
+    /// Max tools running at once (0 = number of CPUs).
+    #[arg(long, default_value_t = 0, global = true)]
+    jobs: usize,
+
+    /// Keep running after a critical failure; only tools depending on a failed stage are skipped.
+    #[arg(long, global = true)]
+    keep_going: bool,
+
+    /// Skip a stage (repeatable), e.g. `--skip coverage`.
+    #[arg(long = "skip", value_name = "STAGE", global = true)]
+    skip: Vec<String>,
+
+    /// Config file (default: `.ci/config.json` when present).
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Coverage below this percentage maps to `warn` (default: config, then 75).
+    #[arg(long, global = true)]
+    coverage_warn: Option<f64>,
+
+    /// Coverage below this percentage maps to `fail` (default: config, then 60).
+    #[arg(long, global = true)]
+    coverage_fail: Option<f64>,
+
+    /// Print the top N CovRank "what to test next" targets after the run.
+    #[arg(long, default_value_t = 0, global = true)]
+    covrank: usize,
+
+    /// Interval between heartbeat messages, in seconds (0 disables heartbeat).
+    #[arg(long, default_value_t = 60, global = true)]
+    heartbeat_sec: u64,
+
+    /// Max runtime per tool before force-kill, in seconds (0 = disabled).
+    #[arg(long, default_value_t = 0, global = true)]
+    timeout_sec: u64,
+
+    /// Consecutive heartbeats with the same last line that count as a hang (0 disables).
+    #[arg(long, default_value_t = 3, global = true)]
+    same_line_hang_pulses: u32,
+
+    /// Minimum runtime before same-line hang detection kicks in, in seconds.
+    #[arg(long, default_value_t = 360, global = true)]
+    same_line_hang_min_sec: u64,
+}
+
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Run all stages plus configured `layers`, and write `.ci_cache/report.json`
+    /// and the `.enforcer/` logs (replaces `build.ps1`).
+    Orchestrate,
+    /// Analyze `.enforcer/Enforcer_stats.log`: recurring rules, trends, stage fail rates.
+    Stats(StatsArgs),
+    /// Compare two schema reports: issues, stage statuses, coverage, durations.
+    Diff(DiffArgs),
+    /// Archived reports in `.ci_cache/history`.
+    #[command(subcommand)]
+    History(HistoryCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum HistoryCommand {
+    /// List archived reports, newest first.
+    List {
+        /// Entries to show.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print an archived report (index or `#index`, commit, or timestamp prefix).
+    Show { id: String },
+}
+
+#[derive(Debug, clap::Args)]
+struct DiffArgs {
+    /// Baseline: report file or history entry (default: `1`, the run before the latest).
+    old: Option<String>,
+
+    /// Report file or history entry to compare (default: `.ci_cache/report.json`).
+    new: Option<String>,
+
+    /// Exit nonzero when anything regressed.
+    #[arg(long)]
+    fail_on_regression: bool,
+
+    /// Stage slowdown (percent) that counts as a duration regression.
+    #[arg(long, default_value_t = 20.0)]
+    duration_pct: f64,
+
+    /// Ignore stage slowdowns below this many milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    duration_min_ms: u64,
+
+    /// Compare even if a report is from a partial run (`--changed-since` / `--staged`).
+    #[arg(long)]
+    allow_partial: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct StatsArgs {
+    /// Stats log to read.
+    #[arg(long, default_value = ".enforcer/Enforcer_stats.log")]
+    log: PathBuf,
+
+    /// First day to include (YYYY-MM-DD, UTC).
+    #[arg(long)]
+    since: Option<NaiveDate>,
+
+    /// Last day to include (YYYY-MM-DD, UTC).
+    #[arg(long)]
+    until: Option<NaiveDate>,
+
+    /// Rules listed per section.
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = StatsFormat::Text)]
+    format: StatsFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum StatsFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl Cli {
+    fn orchestrate(&self) -> bool {
+        matches!(self.command, Some(CliCommand::Orchestrate))
+    }
+
+    /// Worker count; `--fix` runs one tool at a time since fixers rewrite the tree.
+    fn jobs(&self) -> usize {
+        match self.jobs {
+            _ if self.fix => 1,
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    fn use_cache(&self) -> bool {
+        !(self.no_cache || self.force_all)
+    }
+
+    fn heartbeat(&self) -> HeartbeatConfig {
+        HeartbeatConfig {
+            heartbeat_sec: self.heartbeat_sec,
+            timeout_sec: self.timeout_sec,
+            same_line_hang_pulses: self.same_line_hang_pulses,
+            same_line_hang_min_sec: self.same_line_hang_min_sec,
+        }
+    }
+}
+
+// =============================================================================
+// Heartbeat watchdog
+// =============================================================================
+
+/// Watchdog parameters (names mirror `HeartbeatSec`, `TimeoutSec`, etc.).
+#[derive(Clone, Copy, Debug)]
+struct HeartbeatConfig {
+    heartbeat_sec: u64,
+    timeout_sec: u64,
+    same_line_hang_pulses: u32,
+    same_line_hang_min_sec: u64,
+}
+
+/// Output of a watched child process.
+#[derive(Debug, Default)]
+struct Captured {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    hung: bool,
+    timed_out: bool,
+}
+
+enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// How often the watchdog wakes up when the child is silent.
+const WATCHDOG_TICK: Duration = Duration::from_millis(250);
+
+/// How long to keep draining pipes after the child exited or was killed.
+const DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+fn spawn_line_reader<R, F>(pipe: R, tx: Sender<OutputLine>, wrap: F)
+where
+    R: Read + Send + 'static,
+    F: Fn(String) -> OutputLine + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    if tx.send(wrap(line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+fn snippet(line: &str) -> String {
+    let line = line.trim();
+    if line.chars().count() > 60 {
+        let head: String = line.chars().take(57).collect();
+        format!("{head}...")
+    } else {
+        line.to_string()
+    }
+}
+
+/// Puts the child into its own process group so the watchdog can kill the whole tree.
+#[cfg(unix)]
+fn isolate_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn isolate_process_group(_cmd: &mut Command) {}
+
+/// Kills the child together with its process group (Unix) or process tree (Windows).
+fn kill_tree(child: &mut Child) {
+    let pid = child.id().to_string();
+
+    // * Best effort: tree kill first, then the direct child as a fallback.
+    #[cfg(unix)]
+    let _ = Command::new("kill")
+        .args(["-KILL", "--", &format!("-{pid}")])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    #[cfg(windows)]
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    let _ = child.kill();
+}
+
+fn drain(rx: &Receiver<OutputLine>, captured: &mut Captured, deadline: Instant) {
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match rx.recv_timeout(left) {
+            Ok(OutputLine::Stdout(line)) => captured.stdout.push_str(&line),
+            Ok(OutputLine::Stderr(line)) => captured.stderr.push_str(&line),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Runs `cmd` to completion, streaming its output and printing heartbeat lines to stderr.
+///
+/// * Kills the child (and its process group) on timeout or same-line hang.
+/// * Returns `Err` only when the process cannot be spawned.
+fn run_with_heartbeat(
+    label: &str,
+    cmd: &mut Command,
+    hb: &HeartbeatConfig,
+) -> std::io::Result<Captured> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    isolate_process_group(cmd);
+
+    let mut child = cmd.spawn()?;
+    let (tx, rx) = mpsc::channel();
+    if let Some(out) = child.stdout.take() {
+        spawn_line_reader(out, tx.clone(), OutputLine::Stdout);
+    }
+    if let Some(err) = child.stderr.take() {
+        spawn_line_reader(err, tx, OutputLine::Stderr);
+    }
+
+    let started = Instant::now();
+    let heartbeat_every = Duration::from_secs(hb.heartbeat_sec);
+    let mut captured = Captured::default();
+    let mut last_output_at = Instant::now();
+    let mut last_line = String::new();
+    let mut pulse_line: Option<String> = None;
+    let mut same_line_streak: u32 = 0;
+    let mut next_heartbeat = started + heartbeat_every;
+    let mut next_exit_check = started + WATCHDOG_TICK;
+    let mut exited: Option<ExitStatus> = None;
+
+    loop {
+        match rx.recv_timeout(WATCHDOG_TICK) {
+            Ok(OutputLine::Stdout(line)) => {
+                captured.stdout.push_str(&line);
+                last_line = line;
+                last_output_at = Instant::now();
+            }
+            Ok(OutputLine::Stderr(line)) => {
+                captured.stderr.push_str(&line);
+                last_line = format!("[stderr] {line}");
+                last_output_at = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // * Both pipes are closed: the child has exited (or detached its output).
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // * A grandchild may keep the pipes open after the child exits; take what it
+        //   has written so far and stop waiting for it. Polled once per tick.
+        if Instant::now() >= next_exit_check {
+            next_exit_check = Instant::now() + WATCHDOG_TICK;
+            if let Ok(Some(status)) = child.try_wait() {
+                exited = Some(status);
+                drain(&rx, &mut captured, Instant::now() + DRAIN_GRACE);
+                break;
+            }
+        }
+
+        let elapsed = started.elapsed();
+
+        if hb.timeout_sec > 0 && elapsed.as_secs() >= hb.timeout_sec {
+            eprintln!(
+                "[heartbeat] {label} timed out after {}s, terminating...",
+                hb.timeout_sec
+            );
+            captured.timed_out = true;
+            break;
+        }
+
+        if hb.heartbeat_sec == 0 || Instant::now() < next_heartbeat {
+            continue;
+        }
+        next_heartbeat += heartbeat_every;
+
+        eprintln!(
+            "[heartbeat] {label} alive t+{} last-out={}s line='{}' pid={}",
+            format_elapsed(elapsed),
+            last_output_at.elapsed().as_secs(),
+            snippet(&last_line),
+            child.id()
+        );
+
+        if pulse_line.as_deref() == Some(last_line.as_str()) {
+            same_line_streak += 1;
+        } else {
+            same_line_streak = 0;
+            pulse_line = Some(last_line.clone());
+        }
+
+        if hb.same_line_hang_pulses > 0
+            && same_line_streak >= hb.same_line_hang_pulses
+            && elapsed.as_secs() >= hb.same_line_hang_min_sec
+        {
+            eprintln!(
+                "[heartbeat] {label} appears stuck: same output for {same_line_streak} heartbeats, terminating..."
+            );
+            captured.hung = true;
+            break;
+        }
+    }
+
+    if captured.hung || captured.timed_out {
+        kill_tree(&mut child);
+        let _ = child.wait();
+        drain(&rx, &mut captured, Instant::now() + DRAIN_GRACE);
+        captured.exit_code = -1;
+    } else {
+        captured.exit_code = match exited.map_or_else(|| child.wait(), Ok) {
+            Ok(status) => status_to_exit_code(status),
+            Err(_) => 1,
+        };
+    }
+
+    Ok(captured)
+}
+
+// =============================================================================
+// Output parsers
+// =============================================================================
+
+/// Structured data extracted from a tool's output.
+#[derive(Debug, Default)]
+struct ParsedOutput {
+    issues: Vec<Issue>,
+    /// Extra keys merged into the stage `details`.
+    details: Map<String, Value>,
+    /// Overrides the default stage note when set.
+    note: Option<String>,
+    /// Non-blocking findings; a clean exit with warnings maps to `warn`.
+    warnings: usize,
+    /// Policy verdict on a clean exit (e.g. coverage below threshold); for advisories,
+    /// whatever the exit code.
+    status: Option<StageStatus>,
+    test_counts: Option<TestCounts>,
+    coverage: Option<CoverageMetrics>,
+}
+
+/// One compiler/clippy diagnostic at a primary location.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct Diagnostic {
+    rule: String,
+    level: String,
+    file: String,
+    line: u64,
+    column: u64,
+    message: String,
+}
+
+/// Max diagnostics listed in stage `details` (issues still count all of them).
+const MAX_DIAGNOSTIC_DETAILS: usize = 200;
+
+fn parse_output(cfg: &ToolConfig, res: &mut ToolResult, policy: &StagePolicy) -> ParsedOutput {
+    match cfg.parser {
+        OutputParser::Plain => ParsedOutput::default(),
+        OutputParser::CargoDiagnostics => parse_cargo_diagnostics(res),
+        OutputParser::LibTest => parse_test_output(res),
+        OutputParser::Coverage => parse_coverage(res, policy),
+        OutputParser::LegacyJson => parse_legacy_json(res),
+        OutputParser::Advisories => parse_advisories(res, advisory_tool(cfg), &policy.security),
+    }
+}
+
+/// `deny` for a cargo-deny advisories tool, `audit` otherwise (issue `tool` label).
+fn advisory_tool(cfg: &ToolConfig) -> &'static str {
+    if cfg.args.iter().any(|a| a == "deny") {
+        "deny"
+    } else {
+        "audit"
+    }
+}
+
+/// One advisory reported by cargo-audit or cargo-deny.
+#[derive(Debug, Serialize)]
+struct Advisory {
+    id: String,
+    package: String,
+    version: String,
+    /// `vulnerability`, or an informational kind (`unmaintained`, `unsound`, `yanked`, ...).
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    severity: Option<Severity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+    title: String,
+    #[serde(skip)]
+    aliases: Vec<String>,
+}
+
+impl Advisory {
+    fn from_json(advisory: &Value, package: Option<&Value>, kind: &str) -> Self {
+        let text = |value: Option<&Value>, key: &str| -> String {
+            value
+                .and_then(|v| v.get(key))
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let score = advisory
+            .get("cvss")
+            .and_then(Value::as_str)
+            .and_then(cvss3_base_score);
+        Advisory {
+            id: text(Some(advisory), "id"),
+            package: match text(package, "name") {
+                name if name.is_empty() => text(Some(advisory), "package"),
+                name => name,
+            },
+            version: text(package, "version"),
+            kind: kind.to_string(),
+            severity: score.map(severity_from_score),
+            score,
+            title: text(Some(advisory), "title"),
+            aliases: advisory
+                .get("aliases")
+                .and_then(Value::as_array)
+                .map(|a| {
+                    a.iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// CVSS v3.x base score from a vector string (`None` for v2/v4 or malformed vectors).
+fn cvss3_base_score(vector: &str) -> Option<f64> {
+    let rest = vector
+        .strip_prefix("CVSS:3.1/")
+        .or_else(|| vector.strip_prefix("CVSS:3.0/"))?;
+    let metrics: BTreeMap<&str, &str> = rest.split('/').filter_map(|m| m.split_once(':')).collect();
+    let changed = match *metrics.get("S")? {
+        "U" => false,
+        "C" => true,
+        _ => return None,
+    };
+    let cia = |key: &str| match metrics.get(key) {
+        Some(&"H") => Some(0.56),
+        Some(&"L") => Some(0.22),
+        Some(&"N") => Some(0.0),
+        _ => None,
+    };
+    let av = match *metrics.get("AV")? {
+        "N" => 0.85,
+        "A" => 0.62,
+        "L" => 0.55,
+        "P" => 0.2,
+        _ => return None,
+    };
+    let ac = match *metrics.get("AC")? {
+        "L" => 0.77,
+        "H" => 0.44,
+        _ => return None,
+    };
+    let pr = match (*metrics.get("PR")?, changed) {
+        ("N", _) => 0.85,
+        ("L", false) => 0.62,
+        ("L", true) => 0.68,
+        ("H", false) => 0.27,
+        ("H", true) => 0.5,
+        _ => return None,
+    };
+    let ui = match *metrics.get("UI")? {
+        "N" => 0.85,
+        "R" => 0.62,
+        _ => return None,
+    };
+
+    let iss = 1.0 - (1.0 - cia("C")?) * (1.0 - cia("I")?) * (1.0 - cia("A")?);
+    let impact = if changed {
+        7.52 * (iss - 0.029) - 3.25 * (iss - 0.02f64).powi(15)
+    } else {
+        6.42 * iss
+    };
+    if impact <= 0.0 {
+        return Some(0.0);
+    }
+    let exploitability = 8.22 * av * ac * pr * ui;
+    let raw = if changed {
+        (1.08 * (impact + exploitability)).min(10.0)
+    } else {
+        (impact + exploitability).min(10.0)
+    };
+    // * CVSS "round up" to one decimal, done on integers to avoid float artifacts.
+    let scaled = (raw * 100_000.0).round() as u64;
+    Some(if scaled.is_multiple_of(10_000) {
+        scaled as f64 / 100_000.0
+    } else {
+        ((scaled / 10_000) + 1) as f64 / 10.0
+    })
+}
+
+fn severity_from_score(score: f64) -> Severity {
+    match score {
+        s if s >= 9.0 => Severity::Critical,
+        s if s >= 7.0 => Severity::High,
+        s if s >= 4.0 => Severity::Medium,
+        s if s > 0.0 => Severity::Low,
+        _ => Severity::None,
+    }
+}
+
+/// Parses cargo-audit / cargo-deny JSON into advisories, mapped to a stage status.
+///
+/// * Vulnerabilities at or above `fail_severity` (or without a v3 score) fail;
+///   other vulnerabilities and informational advisories warn.
+/// * Ignored advisories are listed in `details` with their reason, not as issues.
+/// * Replaces the JSON stdout with one line per advisory; the exit code is kept and
+///   `stage_from_result` takes the verdict from `parsed.status` instead.
+fn parse_advisories(res: &mut ToolResult, source: &str, policy: &SecurityPolicy) -> ParsedOutput {
+    let mut parsed = ParsedOutput::default();
+    let mut advisories: Vec<Advisory> = Vec::new();
+
+    if let Ok(report) = serde_json::from_str::<Value>(res.stdout.trim()) {
+        // cargo audit --json
+        for vuln in report
+            .pointer("/vulnerabilities/list")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(advisory) = vuln.get("advisory") {
+                advisories.push(Advisory::from_json(
+                    advisory,
+                    vuln.get("package"),
+                    "vulnerability",
+                ));
+            }
+        }
+        for (kind, warnings) in report
+            .get("warnings")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            for warning in warnings.as_array().into_iter().flatten() {
+                // * Yanked crates come with `"advisory": null`.
+                let advisory = warning
+                    .get("advisory")
+                    .filter(|a| !a.is_null())
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "id": kind, "title": format!("{kind} crate") }));
+                advisories.push(Advisory::from_json(&advisory, warning.get("package"), kind));
+            }
+        }
+    } else {
+        // cargo deny --format json check advisories (JSON lines, usually on stderr)
+        let mut saw_json = false;
+        for line in res.stderr.lines().chain(res.stdout.lines()) {
+            let Ok(msg) = serde_json::from_str::<Value>(line) else {
+                continue;
+            };
+            saw_json = true;
+            let Some(fields) = msg.get("fields") else {
+                continue;
+            };
+            if let Some(advisory) = fields.get("advisory") {
+                let kind = fields
+                    .get("code")
+                    .and_then(Value::as_str)
+                    .unwrap_or("vulnerability");
+                let package = fields.pointer("/graphs/0/Krate");
+                advisories.push(Advisory::from_json(advisory, package, kind));
+            }
+        }
+        if !saw_json {
+            return parsed;
+        }
+    }
+
+    let mut ignored: Vec<Value> = Vec::new();
+    let mut used_ignores: BTreeSet<&str> = BTreeSet::new();
+    let mut failing = 0;
+    let mut vulnerabilities = 0;
+    let mut rendered = String::new();
+    advisories.retain(|a| {
+        let ignore = std::iter::once(&a.id)
+            .chain(&a.aliases)
+            .find_map(|id| policy.ignore.get_key_value(id.as_str()));
+        if let Some((id, reason)) = ignore {
+            used_ignores.insert(id);
+            ignored.push(json!({ "id": a.id, "package": a.package, "reason": reason }));
+            return false;
+        }
+        true
+    });
+    for a in &advisories {
+        let severity = a
+            .severity
+            .map(|s| format!("{s:?}").to_lowercase())
+            .unwrap_or_else(|| "unknown".to_string());
+        if a.kind == "vulnerability" {
+            vulnerabilities += 1;
+            if a.severity.is_none_or(|s| s >= policy.fail_severity) {
+                failing += 1;
+            }
+        }
+        rendered.push_str(&format!(
+            "{} {}@{} [{}{}] {}\n",
+            a.id,
+            a.package,
+            a.version,
+            a.kind,
+            if a.kind == "vulnerability" {
+                format!(", {severity}")
+            } else {
+                String::new()
+            },
+            a.title
+        ));
+        parsed.issues.push(Issue {
+            language: "rust".to_string(),
+            tool: source.to_string(),
+            rule: a.id.clone(),
+            count: 1,
+            message: Some(format!(
+                "{} {}: {} ({})",
+                a.package, a.version, a.title, a.kind
+            )),
+        });
+    }
+
+    let informational = advisories.len() - vulnerabilities;
+    parsed.status = Some(if failing > 0 {
+        StageStatus::Fail
+    } else if !advisories.is_empty() {
+        StageStatus::Warn
+    } else {
+        StageStatus::Ok
+    });
+    let mut note =
+        format!("{vulnerabilities} vulnerabilities ({failing} failing), {informational} warnings");
+    if !ignored.is_empty() {
+        note.push_str(&format!(", {} ignored", ignored.len()));
+    }
+    parsed.note = Some(note);
+    parsed
+        .details
+        .insert("advisories".to_string(), json!(advisories));
+    parsed.details.insert("ignored".to_string(), json!(ignored));
+    let unused: Vec<&String> = policy
+        .ignore
+        .keys()
+        .filter(|id| !used_ignores.contains(id.as_str()))
+        .collect();
+    if !unused.is_empty() {
+        parsed
+            .details
+            .insert("unused_ignores".to_string(), json!(unused));
+    }
+    parsed
+        .details
+        .insert("fail_severity".to_string(), json!(policy.fail_severity));
+
+    res.stdout = rendered;
+    parsed
+}
+
+/// Parses the `--json` report of another `build.<lang>` layer.
+///
+/// * Accepts the `build.py` shape (`{<tool>: result, summary}`) and the `build.ts`
+///   shape (`{tools: {<tool>: result}, summary}`), with snake_case or camelCase keys.
+/// * Replaces the JSON stdout/stderr and exit code with those of the inner tool(s).
+fn parse_legacy_json(res: &mut ToolResult) -> ParsedOutput {
+    let mut parsed = ParsedOutput::default();
+    let Ok(report) = serde_json::from_str::<Value>(res.stdout.trim()) else {
+        parsed.note = Some("Layer did not print a JSON report".to_string());
+        if res.exit_code == 0 {
+            res.exit_code = 1;
+        }
+        return parsed;
+    };
+
+    let entries: Vec<(&String, &Value)> = match report.get("tools").and_then(Value::as_object) {
+        Some(tools) => tools.iter().collect(),
+        None => report
+            .as_object()
+            .map(|obj| {
+                obj.iter()
+                    .filter(|(key, value)| *key != "summary" && value.is_object())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+
+    let field = |value: &Value, snake: &str, camel: &str| -> Option<Value> {
+        value.get(snake).or_else(|| value.get(camel)).cloned()
+    };
+
+    let mut exit_code = 0;
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut unavailable: Vec<String> = Vec::new();
+    for (name, result) in &entries {
+        let code = field(result, "exit_code", "exitCode")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(1) as i32;
+        if code != 0 && exit_code == 0 {
+            exit_code = code;
+        }
+        if result.get("available").and_then(Value::as_bool) == Some(false) {
+            unavailable.push((*name).clone());
+        }
+        let text = |key: &str| result.get(key).and_then(Value::as_str).unwrap_or_default();
+        stdout.push_str(text("stdout"));
+        stderr.push_str(text("stderr"));
+        stderr.push_str(text("error"));
+    }
+
+    if entries.is_empty() {
+        parsed.note = Some("Layer report lists no tools".to_string());
+        exit_code = res.exit_code.max(1);
+    } else if !unavailable.is_empty() {
+        parsed.note = Some(format!("Not available: {}", unavailable.join(", ")));
+    }
+    parsed.details.insert(
+        "layer_tools".to_string(),
+        json!(entries.iter().map(|(name, _)| name).collect::<Vec<_>>()),
+    );
+
+    res.exit_code = exit_code;
+    res.stdout = stdout;
+    res.stderr = stderr;
+    parsed
+}
+
+/// Parses `--message-format=json` output into diagnostics and aggregated issues.
+///
+/// * Replaces the raw JSON stdout with the rendered (human-readable) messages.
+fn parse_cargo_diagnostics(res: &mut ToolResult) -> ParsedOutput {
+    let mut diagnostics: BTreeSet<Diagnostic> = BTreeSet::new();
+    let mut rendered = String::new();
+    let mut other_stdout = String::new();
+
+    for line in res.stdout.lines() {
+        let Ok(msg) = serde_json::from_str::<Value>(line) else {
+            other_stdout.push_str(line);
+            other_stdout.push('\n');
+            continue;
+        };
+        if msg.get("reason").and_then(Value::as_str) != Some("compiler-message") {
+            continue;
+        }
+        let Some(message) = msg.get("message") else {
+            continue;
+        };
+
+        // * Summary lines ("aborting due to...", "N warnings emitted") have no spans.
+        let Some(span) = message
+            .get("spans")
+            .and_then(Value::as_array)
+            .and_then(|spans| {
+                spans
+                    .iter()
+                    .find(|s| s.get("is_primary").and_then(Value::as_bool) == Some(true))
+            })
+        else {
+            continue;
+        };
+
+        let level = message
+            .get("level")
+            .and_then(Value::as_str)
+            .unwrap_or("warning")
+            .to_string();
+        let rule = message
+            .get("code")
+            .and_then(|c| c.get("code"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("rustc::{level}"));
+        let diag = Diagnostic {
+            rule,
+            level,
+            file: span
+                .get("file_name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            line: span.get("line_start").and_then(Value::as_u64).unwrap_or(0),
+            column: span
+                .get("column_start")
+                .and_then(Value::as_u64)
+                .unwrap_or(0),
+            message: message
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        };
+
+        // * `--all-targets` reports the same diagnostic once per target; keep one.
+        if diagnostics.insert(diag) {
+            if let Some(text) = message.get("rendered").and_then(Value::as_str) {
+                rendered.push_str(text);
+            }
+        }
+    }
+
+    rendered.push_str(&other_stdout);
+    res.stdout = rendered;
+
+    let mut by_rule: BTreeMap<&str, (usize, &str)> = BTreeMap::new();
+    for diag in &diagnostics {
+        let entry = by_rule
+            .entry(diag.rule.as_str())
+            .or_insert((0, diag.message.as_str()));
+        entry.0 += 1;
+    }
+
+    // * `cargo-clippy` -> `clippy`, `cargo-check` -> `check` (stats log tool label).
+    let tool = res.tool.strip_prefix("cargo-").unwrap_or(&res.tool);
+    let issues: Vec<Issue> = by_rule
+        .iter()
+        .map(|(rule, (count, message))| Issue {
+            language: "rust".to_string(),
+            tool: tool.to_string(),
+            rule: (*rule).to_string(),
+            count: *count,
+            message: Some((*message).to_string()),
+        })
+        .collect();
+
+    let mut parsed = ParsedOutput {
+        warnings: diagnostics.iter().filter(|d| d.level == "warning").count(),
+        ..ParsedOutput::default()
+    };
+    if !diagnostics.is_empty() {
+        parsed.note = Some(format!(
+            "{} diagnostics ({} rules)",
+            diagnostics.len(),
+            issues.len()
+        ));
+        parsed.details.insert(
+            "diagnostics".to_string(),
+            json!(diagnostics
+                .iter()
+                .take(MAX_DIAGNOSTIC_DETAILS)
+                .collect::<Vec<_>>()),
+        );
+        parsed
+            .details
+            .insert("diagnostics_total".to_string(), json!(diagnostics.len()));
+    }
+    parsed.issues = issues;
+    parsed
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FailedTest {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Max failed test names quoted in the stage note.
+const MAX_FAILED_IN_NOTE: usize = 5;
+
+/// Extracts the first number preceding `label` in a summary like `3 passed; 1 failed`.
+fn count_before(text: &str, label: &str) -> Option<u64> {
+    let idx = text.find(label)?;
+    text[..idx]
+        .split(|c: char| !c.is_ascii_digit())
+        .rfind(|part| !part.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// Extracts the panic message from a `---- name stdout ----` section.
+fn panic_message(section: &[&str]) -> Option<String> {
+    let start = section.iter().position(|l| l.contains("panicked at"))?;
+    let header = section[start];
+    let mut lines: Vec<&str> = section[start + 1..]
+        .iter()
+        .take_while(|l| {
+            !l.trim().is_empty() && !l.starts_with("note:") && !l.starts_with("stack backtrace:")
+        })
+        .copied()
+        .collect();
+    // * Pre-1.73 format keeps the message on the header line itself.
+    if lines.is_empty() {
+        lines.push(header.trim());
+    }
+    Some(lines.join("\n"))
+}
+
+/// Parses libtest/nextest output into test counts and a failed-test list.
+///
+/// * Sums `test result:` lines across test binaries (unit, integration, doc tests).
+fn parse_test_output(res: &mut ToolResult) -> ParsedOutput {
+    let text = format!("{}\n{}", res.stdout, res.stderr);
+    let lines: Vec<&str> = text.lines().collect();
+
+    let mut counts = TestCounts::default();
+    let mut seen_summary = false;
+    let mut failed: Vec<String> = Vec::new();
+    let mut messages: BTreeMap<String, String> = BTreeMap::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+
+        if let Ok(event) = serde_json::from_str::<Value>(trimmed) {
+            // * Nightly `--format json`: per-test and per-suite events.
+            let kind = event.get("type").and_then(Value::as_str);
+            let ev = event.get("event").and_then(Value::as_str);
+            if kind == Some("test") && ev == Some("failed") {
+                if let Some(name) = event.get("name").and_then(Value::as_str) {
+                    failed.push(name.to_string());
+                    let out = event.get("stdout").and_then(Value::as_str).unwrap_or("");
+                    let out_lines: Vec<&str> = out.lines().collect();
+                    if let Some(msg) = panic_message(&out_lines) {
+                        messages.insert(name.to_string(), msg);
+                    }
+                }
+            } else if kind == Some("suite") && matches!(ev, Some("ok" | "failed")) {
+                let field = |k: &str| event.get(k).and_then(Value::as_u64).unwrap_or(0);
+                seen_summary = true;
+                counts.passed += field("passed");
+                counts.failed += field("failed");
+                counts.skipped += field("ignored");
+            }
+        } else if let Some(rest) = trimmed.strip_prefix("test result:") {
+            seen_summary = true;
+            counts.passed += count_before(rest, " passed").unwrap_or(0);
+            counts.failed += count_before(rest, " failed").unwrap_or(0);
+            counts.skipped += count_before(rest, " ignored").unwrap_or(0);
+        } else if let Some(name) = trimmed
+            .strip_prefix("test ")
+            .and_then(|r| r.strip_suffix(" ... FAILED"))
+        {
+            failed.push(name.to_string());
+        } else if let Some(name) = trimmed
+            .strip_prefix("---- ")
+            .and_then(|r| r.strip_suffix(" stdout ----"))
+        {
+            let end = lines[i + 1..]
+                .iter()
+                .position(|l| l.starts_with("---- ") || l.trim() == "failures:")
+                .map_or(lines.len(), |p| i + 1 + p);
+            if let Some(msg) = panic_message(&lines[i + 1..end]) {
+                messages.insert(name.to_string(), msg);
+            }
+            i = end;
+            continue;
+        } else if trimmed.starts_with("Summary [") && trimmed.contains(" tests run:") {
+            // * `cargo nextest` final summary replaces per-binary libtest summaries.
+            seen_summary = true;
+            counts.passed = count_before(trimmed, " passed").unwrap_or(0);
+            counts.failed = count_before(trimmed, " failed").unwrap_or(0);
+            counts.skipped = count_before(trimmed, " skipped").unwrap_or(0);
+        } else if let Some(rest) = trimmed.strip_prefix("FAIL [") {
+            if let Some((_, name)) = rest.split_once("] ") {
+                failed.push(name.trim().to_string());
+            }
+        }
+        i += 1;
+    }
+
+    let mut parsed = ParsedOutput::default();
+    if !seen_summary {
+        return parsed;
+    }
+
+    counts.total = counts.passed + counts.failed + counts.skipped;
+    // * nextest repeats every `FAIL` line after its summary.
+    let mut seen: BTreeSet<String> = BTreeSet::new();
+    failed.retain(|name| seen.insert(name.clone()));
+    let failed_tests: Vec<FailedTest> = failed
+        .iter()
+        .map(|name| FailedTest {
+            name: name.clone(),
+            message: messages.get(name).cloned(),
+        })
+        .collect();
+
+    if counts.failed > 0 {
+        let mut names: Vec<&str> = failed
+            .iter()
+            .take(MAX_FAILED_IN_NOTE)
+            .map(String::as_str)
+            .collect();
+        if failed.len() > MAX_FAILED_IN_NOTE {
+            names.push("...");
+        }
+        parsed.note = Some(if names.is_empty() {
+            format!("{} tests failed", counts.failed)
+        } else {
+            format!("{} tests failed: {}", counts.failed, names.join(", "))
+        });
+    } else {
+        parsed.note = Some(format!(
+            "{} passed, {} skipped",
+            counts.passed, counts.skipped
+        ));
+    }
+
+    parsed
+        .details
+        .insert("test_counts".to_string(), json!(counts));
+    parsed
+        .details
+        .insert("failed_tests".to_string(), json!(failed_tests));
+    parsed.test_counts = Some(counts);
+    parsed
+}
+
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Reads `percent` from an llvm-cov totals entry; `None` when nothing was instrumented.
+fn llvm_cov_percent(totals: &Value, key: &str) -> Option<f64> {
+    let entry = totals.get(key)?;
+    if entry.get("count").and_then(Value::as_u64).unwrap_or(0) == 0 {
+        return None;
+    }
+    entry.get("percent").and_then(Value::as_f64).map(round2)
+}
+
+/// Extracts `NN.NN%` from tarpaulin's `NN.NN% coverage, X/Y lines covered` line.
+fn tarpaulin_percent(text: &str) -> Option<f64> {
+    text.lines().rev().find_map(|line| {
+        let (head, _) = line.split_once("% coverage")?;
+        head.rsplit(|c: char| c.is_whitespace())
+            .next()?
+            .parse::<f64>()
+            .ok()
+            .map(round2)
+    })
+}
+
+/// Parses coverage percentages and maps line coverage to `ok`/`warn`/`fail`.
+///
+/// * `>= warn` → ok, `>= fail` → warn, otherwise fail.
+/// * Replaces the (large) llvm-cov JSON stdout with a one-line summary.
+fn parse_coverage(res: &mut ToolResult, policy: &StagePolicy) -> ParsedOutput {
+    let thresholds = &policy.coverage;
+    let mut parsed = ParsedOutput::default();
+
+    let export = serde_json::from_str::<Value>(res.stdout.trim()).ok();
+    let totals = export
+        .as_ref()
+        .and_then(|v| v.pointer("/data/0/totals"))
+        .cloned();
+
+    let (lines, functions, branches) = if let Some(ref totals) = totals {
+        let Some(lines) = llvm_cov_percent(totals, "lines") else {
+            return parsed;
+        };
+        (
+            lines,
+            llvm_cov_percent(totals, "functions"),
+            llvm_cov_percent(totals, "branches"),
+        )
+    } else if let Some(lines) = tarpaulin_percent(&format!("{}\n{}", res.stdout, res.stderr)) {
+        (lines, None, None)
+    } else {
+        return parsed;
+    };
+
+    let (status, rule, threshold) = if lines >= thresholds.warn {
+        (StageStatus::Ok, None, thresholds.warn)
+    } else if lines >= thresholds.fail {
+        (StageStatus::Warn, Some("below_warn"), thresholds.warn)
+    } else {
+        (StageStatus::Fail, Some("below_fail"), thresholds.fail)
+    };
+
+    parsed.note = Some(match rule {
+        None => format!("{lines}% lines"),
+        Some("below_warn") => format!("{lines}% < {threshold}% warn threshold"),
+        Some(_) => format!("{lines}% < {threshold}% fail threshold"),
+    });
+    if let Some(rule) = rule {
+        parsed.issues.push(Issue {
+            language: "ci".to_string(),
+            tool: "coverage".to_string(),
+            rule: rule.to_string(),
+            count: 1,
+            message: Some(format!(
+                "Coverage {lines}% below {threshold}% {} threshold",
+                if status == StageStatus::Fail {
+                    "fail"
+                } else {
+                    "warn"
+                }
+            )),
+        });
+    }
+
+    let metrics = CoverageMetrics {
+        lines_percent: lines,
+        functions_percent: functions,
+        branches_percent: branches,
+        status,
+    };
+    if totals.is_some() {
+        res.stdout = format!(
+            "lines={lines}% functions={} branches={}\n",
+            functions.map_or("n/a".to_string(), |p| format!("{p}%")),
+            branches.map_or("n/a".to_string(), |p| format!("{p}%"))
+        );
+    }
+    parsed.details.insert(
+        "thresholds".to_string(),
+        json!({ "warn": thresholds.warn, "fail": thresholds.fail }),
+    );
+    if let Some(functions) = export
+        .as_ref()
+        .and_then(|v| v.pointer("/data/0/functions"))
+        .and_then(Value::as_array)
+    {
+        let ranked = covrank(functions, policy.covrank_limit);
+        if !ranked.is_empty() {
+            parsed.details.insert("covrank".to_string(), json!(ranked));
+        }
+    }
+    parsed.status = Some(status);
+    parsed.coverage = Some(metrics);
+    parsed
+}
+
+// =============================================================================
+// CovRank ("what to test next")
+// =============================================================================
+
+/// A low-coverage, high-impact test target.
+#[derive(Debug, Clone, Serialize)]
+struct CovRankEntry {
+    function: String,
+    file: String,
+    line: u64,
+    size_lines: u64,
+    uncovered_lines: u64,
+    churn: u64,
+    score: u64,
+}
+
+/// Commits touching each file within `COVRANK_CHURN_SINCE` (empty outside git).
+fn git_churn() -> BTreeMap<String, u64> {
+    let mut churn = BTreeMap::new();
+    let Ok(out) = Command::new("git")
+        .args([
+            "log",
+            &format!("--since={COVRANK_CHURN_SINCE}"),
+            "--format=",
+            "--name-only",
+        ])
+        .stderr(Stdio::null())
+        .output()
+    else {
+        return churn;
+    };
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let line = line.trim();
+        if !line.is_empty() {
+            *churn.entry(line.to_string()).or_insert(0) += 1;
+        }
+    }
+    churn
+}
+
+/// Makes an llvm-cov file path relative to the current directory, with `/` separators.
+fn relative_path(path: &str) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    Path::new(path)
+        .strip_prefix(&cwd)
+        .unwrap_or(Path::new(path))
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Demangles a symbol; llvm-cov prefixes local symbols with `<file>:`.
+fn demangle_symbol(name: &str) -> String {
+    let symbol = name
+        .rsplit_once(':')
+        .filter(|(_, sym)| sym.starts_with("_ZN") || sym.starts_with("_R"))
+        .map_or(name, |(_, sym)| sym);
+    format!("{:#}", rustc_demangle::demangle(symbol))
+}
+
+/// Ranks functions by `uncovered lines × function size × (1 + churn)`.
+///
+/// * Uses code regions of the function's own file (`fileID == 0`, `kind == 0`).
+/// * Generic instantiations share a location; the best-covered instance wins.
+fn covrank(functions: &[Value], limit: usize) -> Vec<CovRankEntry> {
+    let churn = git_churn();
+    let mut by_location: BTreeMap<(String, u64), CovRankEntry> = BTreeMap::new();
+
+    for func in functions {
+        let Some(file) = func
+            .get("filenames")
+            .and_then(Value::as_array)
+            .and_then(|f| f.first())
+            .and_then(Value::as_str)
+        else {
+            continue;
+        };
+        let Some(regions) = func.get("regions").and_then(Value::as_array) else {
+            continue;
+        };
+
+        let mut covered: BTreeSet<u64> = BTreeSet::new();
+        let mut uncovered: BTreeSet<u64> = BTreeSet::new();
+        let (mut first, mut last) = (u64::MAX, 0u64);
+        for region in regions.iter().filter_map(Value::as_array) {
+            let field = |i: usize| region.get(i).and_then(Value::as_u64).unwrap_or(0);
+            let (start, end, count, file_id, kind) =
+                (field(0), field(2), field(4), field(5), field(7));
+            if file_id != 0 || kind != 0 || start == 0 {
+                continue;
+            }
+            first = first.min(start);
+            last = last.max(end);
+            let target = if count > 0 {
+                &mut covered
+            } else {
+                &mut uncovered
+            };
+            target.extend(start..=end);
+        }
+        if first > last {
+            continue;
+        }
+
+        let file = relative_path(file);
+        let uncovered_lines = uncovered.difference(&covered).count() as u64;
+        let size_lines = last - first + 1;
+        let file_churn = churn.get(&file).copied().unwrap_or(0);
+        let entry = CovRankEntry {
+            function: demangle_symbol(func.get("name").and_then(Value::as_str).unwrap_or("?")),
+            file: file.clone(),
+            line: first,
+            size_lines,
+            uncovered_lines,
+            churn: file_churn,
+            score: uncovered_lines * size_lines * (1 + file_churn),
+        };
+
+        by_location
+            .entry((file, first))
+            .and_modify(|e| {
+                if entry.uncovered_lines < e.uncovered_lines {
+                    *e = entry.clone();
+                }
+            })
+            .or_insert(entry);
+    }
+
+    let mut ranked: Vec<CovRankEntry> = by_location
+        .into_values()
+        .filter(|e| e.uncovered_lines > 0)
+        .collect();
+    ranked.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.file.cmp(&b.file)));
+    ranked.truncate(limit);
+    ranked
+}
+
+fn print_covrank(stages: &[StageResult], top: usize) {
+    let Some(entries) = stages
+        .iter()
+        .filter(|s| s.name == "coverage")
+        .find_map(|s| s.details.as_ref()?.get("covrank")?.as_array())
+    else {
+        eprintln!("[covrank] no per-function coverage data (coverage stage not run or cached)");
+        return;
+    };
+
+    eprintln!(
+        "[covrank] top {} targets to test next:",
+        top.min(entries.len())
+    );
+    for (i, e) in entries.iter().take(top).enumerate() {
+        let field = |k: &str| e.get(k).and_then(Value::as_u64).unwrap_or(0);
+        eprintln!(
+            "  {:>2}. {}:{} {} (uncovered {}/{} lines, churn {}, score {})",
+            i + 1,
+            e.get("file").and_then(Value::as_str).unwrap_or("?"),
+            field("line"),
+            e.get("function").and_then(Value::as_str).unwrap_or("?"),
+            field("uncovered_lines"),
+            field("size_lines"),
+            field("churn"),
+            field("score"),
+        );
+    }
+}
+
+// =============================================================================
+// Built-in: line-limits
+// =============================================================================
+
+/// Strips comments and string contents from Rust source, one entry per line.
+///
+/// * Doc comments (`///`, `//!`, `/** */`) are comments too.
+/// * Nested block comments and raw strings (`r#"..."#`) are tracked across lines.
+fn rust_code_lines(source: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut block_depth = 0usize;
+    // * `Some(n)`: inside a string; `n` is the raw-string hash count (`None` inside = plain).
+    let mut string: Option<Option<usize>> = None;
+
+    for line in source.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut code = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if block_depth > 0 {
+                if c == '*' && next == Some('/') {
+                    block_depth -= 1;
+                    i += 2;
+                } else if c == '/' && next == Some('*') {
+                    block_depth += 1;
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+            match string {
+                Some(None) => {
+                    if c == '\\' {
+                        i += 2;
+                        continue;
+                    }
+                    if c == '"' {
+                        string = None;
+                        code.push('"');
+                    }
+                    i += 1;
+                    continue;
+                }
+                Some(Some(hashes)) => {
+                    if c == '"'
+                        && chars[i + 1..]
+                            .iter()
+                            .take(hashes)
+                            .filter(|h| **h == '#')
+                            .count()
+                            == hashes
+                    {
+                        string = None;
+                        code.push('"');
+                        i += 1 + hashes;
+                    } else {
+                        i += 1;
+                    }
+                    continue;
+                }
+                None => {}
+            }
+            if c == '/' && next == Some('/') {
+                break;
+            }
+            if c == '/' && next == Some('*') {
+                block_depth += 1;
+                i += 2;
+                continue;
+            }
+            if c == '"' {
+                string = Some(None);
+                code.push('"');
+                i += 1;
+                continue;
+            }
+            // * Raw strings may carry a `b`/`c` prefix (`br#"..."#`, `cr"..."`).
+            let prefixed = i > 0 && matches!(chars[i - 1], 'b' | 'c');
+            let start = if prefixed { i - 1 } else { i };
+            if c == 'r'
+                && matches!(next, Some('"' | '#'))
+                && !chars
+                    .get(start.wrapping_sub(1))
+                    .is_some_and(|p| p.is_alphanumeric() || *p == '_')
+            {
+                let hashes = chars[i + 1..].iter().take_while(|h| **h == '#').count();
+                if chars.get(i + 1 + hashes) == Some(&'"') {
+                    string = Some(Some(hashes));
+                    code.push('"');
+                    i += 2 + hashes;
+                    continue;
+                }
+            }
+            if c == '\'' {
+                // * Char literals (`'{'`, `'\''`) must not affect brace counting; lifetimes pass through.
+                if next == Some('\\') {
+                    // * The escaped char itself may be a quote, so the search starts after it.
+                    if let Some(end) = chars
+                        .get(i + 3..)
+                        .and_then(|rest| rest.iter().position(|ch| *ch == '\''))
+                    {
+                        code.push_str("' '");
+                        i += end + 4;
+                        continue;
+                    }
+                } else if chars.get(i + 2) == Some(&'\'') {
+                    code.push_str("' '");
+                    i += 3;
+                    continue;
+                }
+            }
+            code.push(c);
+            i += 1;
+        }
+        out.push(code);
+    }
+    out
+}
+
+fn is_mod_item(item: &str) -> bool {
+    ["mod ", "pub mod ", "pub(crate) mod "]
+        .iter()
+        .any(|prefix| item.starts_with(prefix))
+}
+
+/// Counts executable lines: non-blank after stripping comments and doc comments.
+///
+/// * `#[cfg(test)]` modules are skipped whether the attribute shares the `mod` line
+///   or not, and whether the opening brace is on the `mod` line or the next one.
+fn count_executable_lines(source: &str, skip_test_modules: bool) -> usize {
+    let mut count = 0;
+    let mut depth: i64 = 0;
+    let mut cfg_test_pending = false;
+    let mut awaiting_brace = false;
+    // * Lines held back until we know whether they open a test module.
+    let mut held = 0usize;
+    let mut skip_until_depth: Option<i64> = None;
+
+    for code in rust_code_lines(source) {
+        let trimmed = code.trim();
+        let depth_before = depth;
+        depth += code.matches('{').count() as i64 - code.matches('}').count() as i64;
+
+        if let Some(target) = skip_until_depth {
+            if depth <= target {
+                skip_until_depth = None;
+            }
+            continue;
+        }
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if skip_test_modules {
+            let item = match trimmed.strip_prefix("#[cfg(test)]") {
+                Some(rest) => {
+                    count += std::mem::take(&mut held);
+                    awaiting_brace = false;
+                    cfg_test_pending = true;
+                    rest.trim()
+                }
+                None => trimmed,
+            };
+            let opens_module = if awaiting_brace {
+                awaiting_brace = false;
+                item.starts_with('{')
+            } else if cfg_test_pending && !item.is_empty() {
+                cfg_test_pending = false;
+                if is_mod_item(item) && !item.contains('{') && !item.ends_with(';') {
+                    held += 1;
+                    awaiting_brace = true;
+                    continue;
+                }
+                is_mod_item(item) && item.ends_with('{')
+            } else if cfg_test_pending {
+                held += 1;
+                continue;
+            } else {
+                false
+            };
+            if opens_module {
+                held = 0;
+                if depth > depth_before {
+                    skip_until_depth = Some(depth_before);
+                }
+                continue;
+            }
+            // * `#[cfg(test)]` on something else: count the lines we held back.
+            count += std::mem::take(&mut held);
+        }
+        count += 1;
+    }
+    count
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LineOffender {
+    path: String,
+    value: usize,
+    status: StageStatus,
+}
+
+fn grade(value: usize, warn: usize, fail: usize) -> StageStatus {
+    if value > fail {
+        StageStatus::Fail
+    } else if value > warn {
+        StageStatus::Warn
+    } else {
+        StageStatus::Ok
+    }
+}
+
+/// Checks executable lines per Rust file and files per directory under `target_paths`.
+///
+/// * Returns the top offenders as `stdout` for the caller to print; writes the full
+///   table to `LOG_DIR`.
+fn run_line_limits(
+    tool_name: &str,
+    cfg: &ToolConfig,
+    target_paths: &[String],
+    limits: &LineLimits,
+    untracked: bool,
+) -> (ToolResult, ParsedOutput) {
+    let started = Instant::now();
+    let mut result = ToolResult {
+        tool: tool_name.to_string(),
+        description: cfg.description.to_string(),
+        command: String::new(),
+        available: true,
+        exit_code: 0,
+        stdout: String::new(),
+        stderr: String::new(),
+        critical: cfg.critical,
+        can_fix: false,
+        fixed: false,
+        hung: false,
+        timed_out: false,
+        duration_ms: 0,
+        log_path: None,
+        fixed_files: Vec::new(),
+    };
+    let mut parsed = ParsedOutput::default();
+
+    // * Single files (a `--changed-since` scope) still grade their whole directory.
+    let file_dirs: BTreeSet<PathBuf> = target_paths
+        .iter()
+        .map(Path::new)
+        .filter(|path| path.is_file())
+        .filter_map(|path| path.parent().map(normalize_path))
+        .collect();
+    let dir_roots: Vec<String> = file_dirs
+        .iter()
+        .map(|dir| match dir.to_string_lossy() {
+            name if name.is_empty() => ".".to_string(),
+            name => name.to_string(),
+        })
+        .collect();
+    let discovered = discover_files(target_paths, untracked)
+        .and_then(|files| Ok((files, discover_files(&dir_roots, untracked)?)));
+    let (files, dir_files) = match discovered {
+        Ok(found) => found,
+        Err(err) => {
+            result.exit_code = 1;
+            result.stderr = format!("{err:#}");
+            result.duration_ms = started.elapsed().as_millis();
+            return (result, parsed);
+        }
+    };
+
+    let mut file_offenders: Vec<LineOffender> = Vec::new();
+    let mut per_dir: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    let mut rust_files = 0usize;
+    for file in &files {
+        if let Some(parent) = file.parent() {
+            *per_dir.entry(parent.to_path_buf()).or_insert(0) += 1;
+        }
+        if file.extension().and_then(|e| e.to_str()) != Some("rs") {
+            continue;
+        }
+        let Ok(source) = fs::read_to_string(file) else {
+            continue;
+        };
+        rust_files += 1;
+        let lines = count_executable_lines(&source, limits.skip_test_modules);
+        file_offenders.push(LineOffender {
+            path: file.to_string_lossy().replace('\\', "/"),
+            value: lines,
+            status: grade(lines, limits.warn_lines, limits.fail_lines),
+        });
+    }
+    for dir in file_dirs {
+        let count = dir_files
+            .iter()
+            .filter(|file| file.parent() == Some(dir.as_path()))
+            .count();
+        per_dir.insert(dir, count);
+    }
+    let mut dir_offenders: Vec<LineOffender> = per_dir
+        .into_iter()
+        .map(|(dir, n)| LineOffender {
+            path: match dir.to_string_lossy().replace('\\', "/") {
+                root if root.is_empty() => ".".to_string(),
+                path => path,
+            },
+            value: n,
+            status: grade(
+                n,
+                limits.max_files_per_dir_warn,
+                limits.max_files_per_dir_fail,
+            ),
+        })
+        .collect();
+
+    file_offenders.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.path.cmp(&b.path)));
+    dir_offenders.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.path.cmp(&b.path)));
+
+    // * Full table goes to the log (every file and directory, not just offenders).
+    let mut log = format!(
+        "line-limits: warn>{} fail>{} lines; warn>{} fail>{} files/dir; skip_test_modules={}\n\n",
+        limits.warn_lines,
+        limits.fail_lines,
+        limits.max_files_per_dir_warn,
+        limits.max_files_per_dir_fail,
+        limits.skip_test_modules
+    );
+    log.push_str("# executable lines per file\n");
+    for o in &file_offenders {
+        log.push_str(&format!(
+            "{:>6}  {:<4}  {}\n",
+            o.value,
+            o.status.as_str(),
+            o.path
+        ));
+    }
+    log.push_str("\n# files per directory\n");
+    for o in &dir_offenders {
+        log.push_str(&format!(
+            "{:>6}  {:<4}  {}\n",
+            o.value,
+            o.status.as_str(),
+            o.path
+        ));
+    }
+    let log_path = Path::new(LOG_DIR).join(format!("{tool_name}_{}.log", log_timestamp()));
+    let log_written = fs::create_dir_all(LOG_DIR).and_then(|_| fs::write(&log_path, log));
+    let log_path = log_path.to_string_lossy().replace('\\', "/");
+
+    let file_bad: Vec<&LineOffender> = file_offenders
+        .iter()
+        .filter(|o| o.status != StageStatus::Ok)
+        .collect();
+    let dir_bad: Vec<&LineOffender> = dir_offenders
+        .iter()
+        .filter(|o| o.status != StageStatus::Ok)
+        .collect();
+
+    for (label, unit, bad) in [("file", "lines", &file_bad), ("dir", "files", &dir_bad)] {
+        for status in [StageStatus::Fail, StageStatus::Warn] {
+            let hits: Vec<&&LineOffender> = bad.iter().filter(|o| o.status == status).collect();
+            if let Some(first) = hits.first() {
+                parsed.issues.push(Issue {
+                    language: "rust".to_string(),
+                    tool: "line-limits".to_string(),
+                    rule: format!("{label}_{unit}_{}", status.as_str()),
+                    count: hits.len(),
+                    message: Some(format!("{} has {} {unit}", first.path, first.value)),
+                });
+            }
+        }
+    }
+
+    let worst = if file_bad
+        .iter()
+        .chain(&dir_bad)
+        .any(|o| o.status == StageStatus::Fail)
+    {
+        StageStatus::Fail
+    } else if file_bad.is_empty() && dir_bad.is_empty() {
+        StageStatus::Ok
+    } else {
+        StageStatus::Warn
+    };
+
+    let mut summary = String::new();
+    for o in file_bad.iter().take(TOP_OFFENDERS) {
+        summary.push_str(&format!(
+            "[line-limits] {} {} lines ({})\n",
+            o.path,
+            o.value,
+            o.status.as_str()
+        ));
+    }
+    for o in dir_bad.iter().take(TOP_OFFENDERS) {
+        summary.push_str(&format!(
+            "[line-limits] {}/ {} files ({})\n",
+            o.path,
+            o.value,
+            o.status.as_str()
+        ));
+    }
+    if !summary.is_empty() {
+        summary.push_str(&format!("[line-limits] full details: {log_path}\n"));
+    }
+    result.stdout = summary;
+
+    parsed.note = Some(if worst == StageStatus::Ok {
+        format!("{rust_files} files within limits")
+    } else {
+        let top = file_bad
+            .first()
+            .or(dir_bad.first())
+            .map(|o| format!("; top: {} ({})", o.path, o.value))
+            .unwrap_or_default();
+        format!(
+            "{} files, {} dirs over limits{top}; see {log_path}",
+            file_bad.len(),
+            dir_bad.len()
+        )
+    });
+    parsed.status = Some(worst);
+    parsed.details.insert(
+        "top_files".to_string(),
+        json!(file_offenders
+            .iter()
+            .take(TOP_OFFENDERS)
+            .collect::<Vec<_>>()),
+    );
+    parsed.details.insert(
+        "top_dirs".to_string(),
+        json!(dir_offenders.iter().take(TOP_OFFENDERS).collect::<Vec<_>>()),
+    );
+    if log_written.is_ok() {
+        result.log_path = Some(log_path);
+    }
+
+    result.duration_ms = started.elapsed().as_millis();
+    (result, parsed)
+}
+
+// =============================================================================
+// File discovery (git ls-files, .gitmodules)
+// =============================================================================
+
+/// Submodule paths from `.gitmodules`; only the `path = ...` keys are read.
+fn submodule_paths() -> Vec<PathBuf> {
+    let Ok(text) = fs::read_to_string(".gitmodules") else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "path").then(|| normalize_path(Path::new(value.trim())))
+        })
+        .collect()
+}
+
+/// `path` without `.` components, so `./src/lib.rs` and `src/lib.rs` compare equal.
+fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Whether discovery skips `path` (relative to the project root): inside a submodule or
+/// an excluded directory.
+///
+/// * `EXCLUDED_DIRS` count at the root only, plus `target` next to a crate's `Cargo.toml`
+///   and `.git` anywhere; a source module such as `src/target/` is still listed.
+fn is_excluded(path: &Path, submodules: &[PathBuf]) -> bool {
+    if submodules.iter().any(|sub| path.starts_with(sub)) {
+        return true;
+    }
+    let mut parent = PathBuf::new();
+    for component in path.components() {
+        let name = component.as_os_str();
+        let at_root = parent.as_os_str().is_empty();
+        if name == ".git"
+            || (at_root && EXCLUDED_DIRS.iter().any(|dir| name == *dir))
+            || (name == "target" && parent.join("Cargo.toml").is_file())
+        {
+            return true;
+        }
+        parent.push(component);
+    }
+    false
+}
+
+/// Lists the files under `roots` (files or directories), sorted and relative to the
+/// current directory.
+///
+/// * In git: `git ls-files` (tracked files, plus untracked-not-ignored ones with
+///   `untracked`), so ignored files never count.
+/// * Outside git: a directory walker.
+/// * Both skip `.gitmodules` submodule paths and `EXCLUDED_DIRS` (`target/`, `.ci_cache/`, ...).
+fn discover_files(roots: &[String], untracked: bool) -> Result<Vec<PathBuf>> {
+    if roots.is_empty() {
+        return Ok(Vec::new());
+    }
+    let submodules = submodule_paths();
+    let mut args = vec!["ls-files", "-z", "--cached"];
+    if untracked {
+        args.extend(["--others", "--exclude-standard"]);
+    }
+    args.push("--");
+    args.extend(roots.iter().map(String::as_str));
+    let git = Command::new("git")
+        .args(&args)
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success());
+
+    let mut files: Vec<PathBuf> = match git {
+        // * Deleted files and submodule gitlinks are listed too; `is_file` drops them.
+        Some(out) => String::from_utf8_lossy(&out.stdout)
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .filter(|path| path.is_file())
+            .collect(),
+        None => {
+            let mut files = Vec::new();
+            for root in roots {
+                let path = Path::new(root);
+                if path.is_dir() {
+                    walk_files(path, &submodules, &mut files)?;
+                } else if path.is_file() {
+                    files.push(normalize_path(path));
+                }
+            }
+            files
+        }
+    };
+    files.retain(|path| !is_excluded(path, &submodules));
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Fallback for `discover_files` outside git.
+fn walk_files(dir: &Path, submodules: &[PathBuf], out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = normalize_path(&entry?.path());
+        if path.is_dir() {
+            if !is_excluded(&path, submodules) {
+                walk_files(&path, submodules, out)?;
+            }
+        } else if path.is_file() {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Caching (hash guards + trust stamps)
+// =============================================================================
+
+/// Computes the SHA-256 over sorted input paths and their contents.
+///
+/// * Cargo tools build the whole workspace regardless of `--path`, so the hash always
+///   covers all `target_dirs`, the workspace manifests and the tool's `cache_inputs`.
+/// * The tool command line is part of the hash, so changing args invalidates the cache.
+fn compute_inputs_hash(
+    cfg: &ToolConfig,
+    target_dirs: &[String],
+    untracked: bool,
+) -> Result<String> {
+    let mut files = discover_files(target_dirs, untracked)?;
+    // * Manifests and explicit inputs (e.g. the config file) count even when ignored.
+    let extras = HASH_MANIFESTS
+        .iter()
+        .copied()
+        .chain(cfg.cache_inputs.iter().map(String::as_str));
+    for extra in extras {
+        let path = Path::new(extra);
+        if path.is_dir() {
+            files.extend(discover_files(&[extra.to_string()], untracked)?);
+        } else if path.is_file() {
+            files.push(normalize_path(path));
+        }
+    }
+    files.sort();
+    files.dedup();
+
+    let mut hasher = Sha256::new();
+    hasher.update(cfg.command.as_bytes());
+    for arg in &cfg.args {
+        hasher.update([0u8]);
+        hasher.update(arg.as_bytes());
+    }
+    for file in &files {
+        let bytes = fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+        hasher.update(file.to_string_lossy().replace('\\', "/").as_bytes());
+        hasher.update([0u8]);
+        hasher.update(&bytes);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+fn hash_file(cache_dir: &Path, tool_name: &str) -> PathBuf {
+    cache_dir.join(format!("{tool_name}.sha256"))
+}
+
+fn trust_file(cache_dir: &Path, tool_name: &str) -> PathBuf {
+    cache_dir.join(format!("{tool_name}.trusted"))
+}
+
+/// Cache hit: stored hash matches and a trust stamp exists.
+fn is_cache_hit(cache_dir: &Path, tool_name: &str, hash: &str) -> bool {
+    let stored = fs::read_to_string(hash_file(cache_dir, tool_name)).unwrap_or_default();
+    stored.trim() == hash && trust_file(cache_dir, tool_name).is_file()
+}
+
+fn write_cache_stamp(cache_dir: &Path, tool_name: &str, hash: &str) -> Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    fs::write(hash_file(cache_dir, tool_name), format!("{hash}\n"))?;
+    fs::write(trust_file(cache_dir, tool_name), "")?;
+    Ok(())
+}
+
+fn clear_trust_stamp(cache_dir: &Path, tool_name: &str) {
+    let _ = fs::remove_file(trust_file(cache_dir, tool_name));
+}
+
+/// Deletes everything in `CACHE_DIR` except the report archive.
+fn clean_cache_dir() -> Result<()> {
+    let Ok(entries) = fs::read_dir(CACHE_DIR) else {
+        return Ok(());
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == Path::new(HISTORY_DIR) {
+            continue;
+        }
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        removed.with_context(|| format!("Failed to delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Report files (.ci_cache/report.json, .enforcer/)
+// =============================================================================
+
+/// Writes `REPORT_PATH` and archives a copy in `HISTORY_DIR` (keeping `history_max`).
+fn write_report(report: &Report, history_max: usize) -> Result<()> {
+    let json = format!("{}\n", serde_json::to_string_pretty(report)?);
+    fs::create_dir_all(CACHE_DIR)?;
+    fs::write(REPORT_PATH, &json).with_context(|| format!("Failed to write {REPORT_PATH}"))?;
+    if history_max > 0 {
+        archive_report(&json, &report.finished_at_utc, history_max)?;
+    }
+    Ok(())
+}
+
+/// Second-precision UTC timestamp for the stats log.
+fn stats_timestamp(rfc3339: &str) -> String {
+    DateTime::parse_from_rfc3339(rfc3339)
+        .map(|ts| {
+            ts.with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+        })
+        .unwrap_or_else(|_| rfc3339.to_string())
+}
+
+/// One stats line: `rust: [clippy] rule — message (xN)`.
+fn stats_line(issue: &Issue) -> String {
+    let message = issue
+        .message
+        .as_deref()
+        .map(|m| format!(" — {}", m.split_whitespace().collect::<Vec<_>>().join(" ")))
+        .unwrap_or_default();
+    format!(
+        "{}: [{}] {}{message} (x{})",
+        issue.language, issue.tool, issue.rule, issue.count
+    )
+}
+
+/// `fmt=ok lint=fail ...`; a stage implemented by several tools gets its worst status.
+fn stats_stages(stages: &[StageResult]) -> String {
+    let severity = |status: StageStatus| match status {
+        StageStatus::Fail => 4,
+        StageStatus::Warn => 3,
+        StageStatus::Ok => 2,
+        StageStatus::Cached => 1,
+        StageStatus::Skip => 0,
+    };
+    let mut order: Vec<&str> = Vec::new();
+    let mut worst: BTreeMap<&str, StageStatus> = BTreeMap::new();
+    for stage in stages {
+        match worst.get_mut(stage.name.as_str()) {
+            Some(status) if severity(stage.status) > severity(*status) => *status = stage.status,
+            Some(_) => {}
+            None => {
+                order.push(&stage.name);
+                worst.insert(&stage.name, stage.status);
+            }
+        }
+    }
+    order
+        .iter()
+        .map(|name| format!("{name}={}", worst[name].as_str()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Overwrites `Enforcer_last_check.log` with the report and appends a block to
+/// `Enforcer_stats.log` (see `docs/en/REPORT_FORMAT.md`).
+///
+/// * The stats file is append-only and never rotated; clean it up manually after review.
+fn write_enforcer_logs(report: &Report) -> Result<()> {
+    let dir = Path::new(ENFORCER_DIR);
+    fs::create_dir_all(dir)?;
+    let json = serde_json::to_string_pretty(report)?;
+    fs::write(dir.join("Enforcer_last_check.log"), format!("{json}\n"))?;
+
+    let mut block = format!(
+        "--- Check started at {} ---\n",
+        stats_timestamp(&report.started_at_utc)
+    );
+    // * `build stats` leaves partial runs out of fail rates.
+    if let Some(ref scope) = report.scope {
+        block.push_str(&format!("--- Scope: partial, {} ---\n", scope.describe()));
+    }
+    for issue in &report.issues {
+        block.push_str(&stats_line(issue));
+        block.push('\n');
+    }
+    block.push_str(&format!(
+        "--- Stages: {} ---\n",
+        stats_stages(&report.stages)
+    ));
+    block.push_str(&format!(
+        "--- Check finished at {} (status={}) ---\n\n",
+        stats_timestamp(&report.finished_at_utc),
+        report.status.as_str()
+    ));
+
+    // * One `write_all` per run keeps blocks from concurrent runs from interleaving.
+    let stats_path = dir.join("Enforcer_stats.log");
+    let mut stats = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&stats_path)
+        .with_context(|| format!("Failed to open {}", stats_path.display()))?;
+    stats.write_all(block.as_bytes())?;
+    Ok(())
+}
+
+// =============================================================================
+// Stats (Enforcer_stats.log review)
+// =============================================================================
+
+/// One `--- Check started ... --- / --- Check finished ... ---` block.
+#[derive(Debug, Default)]
+struct StatsRun {
+    started: Option<DateTime<Utc>>,
+    /// `None` when the run never finished (crash, Ctrl+C).
+    status: Option<String>,
+    /// Stage -> status; empty for blocks written before the `Stages` line existed.
+    stages: BTreeMap<String, String>,
+    /// `--- Scope: partial, ... ---`: a `--changed-since`/`--staged` run.
+    partial: bool,
+    /// `language: [tool] rule` -> count.
+    issues: BTreeMap<String, u64>,
+}
+
+/// Parses `rust: [clippy] rule — message (xN)` into (`rust: [clippy] rule`, N).
+fn parse_stats_issue(line: &str) -> Option<(String, u64)> {
+    let (head, tail) = line.rsplit_once(" (x")?;
+    let count = tail.strip_suffix(')')?.parse().ok()?;
+    let (language, rest) = head.split_once(": [")?;
+    let (tool, rest) = rest.split_once("] ")?;
+    let rule = rest.split(" — ").next()?.trim();
+    Some((format!("{language}: [{tool}] {rule}"), count))
+}
+
+fn parse_stats_log(text: &str) -> Vec<StatsRun> {
+    let mut runs: Vec<StatsRun> = Vec::new();
+    for line in text.lines().map(str::trim_end) {
+        if let Some(rest) = line.strip_prefix("--- Check started at ") {
+            let ts = rest.trim_end_matches(" ---");
+            runs.push(StatsRun {
+                started: DateTime::parse_from_rfc3339(ts)
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc)),
+                ..StatsRun::default()
+            });
+        } else if line.starts_with("--- Scope: partial") {
+            if let Some(run) = runs.last_mut() {
+                run.partial = true;
+            }
+        } else if let Some(rest) = line.strip_prefix("--- Stages: ") {
+            if let Some(run) = runs.last_mut() {
+                for pair in rest.trim_end_matches(" ---").split_whitespace() {
+                    if let Some((stage, status)) = pair.split_once('=') {
+                        run.stages.insert(stage.to_string(), status.to_string());
+                    }
+                }
+            }
+        } else if line.starts_with("--- Check finished at ") {
+            if let (Some(run), Some((_, status))) = (runs.last_mut(), line.split_once("(status=")) {
+                run.status = Some(status.trim_end_matches(") ---").to_string());
+            }
+        } else if let (Some(run), Some((key, count))) = (runs.last_mut(), parse_stats_issue(line)) {
+            *run.issues.entry(key).or_default() += count;
+        }
+    }
+    runs
+}
+
+#[derive(Debug, Serialize)]
+struct RuleStats {
+    rule: String,
+    /// Runs in which the rule appeared.
+    runs: u64,
+    total_count: u64,
+    /// Average count per run in the first / second half of the range.
+    first_half_per_run: f64,
+    second_half_per_run: f64,
+    trend: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct StageStats {
+    stage: String,
+    /// Runs where the stage actually ran (`ok`/`warn`/`fail`).
+    runs: u64,
+    failed: u64,
+    /// `failed / runs`, unrounded; rounded only when printed.
+    fail_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsSummary {
+    runs: u64,
+    /// Failed full runs; partial runs are not in any fail rate.
+    failed_runs: u64,
+    partial_runs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    top_rules: Vec<RuleStats>,
+    trending_up: Vec<RuleStats>,
+    trending_down: Vec<RuleStats>,
+    stages: Vec<StageStats>,
+}
+
+/// Aggregates runs within `since..=until`; trends compare the two halves of the range.
+fn summarize_stats(runs: &[StatsRun], args: &StatsArgs) -> StatsSummary {
+    let runs: Vec<&StatsRun> = runs
+        .iter()
+        .filter(|run| {
+            let day = run.started.map(|t| t.date_naive());
+            args.since
+                .is_none_or(|since| day.is_some_and(|d| d >= since))
+                && args
+                    .until
+                    .is_none_or(|until| day.is_some_and(|d| d <= until))
+        })
+        .collect();
+
+    let from = runs.iter().filter_map(|r| r.started).min();
+    let to = runs.iter().filter_map(|r| r.started).max();
+    let midpoint = from.zip(to).map(|(from, to)| from + (to - from) / 2);
+    let (first, second): (Vec<&StatsRun>, Vec<&StatsRun>) = runs
+        .iter()
+        .partition(|run| run.started.zip(midpoint).is_some_and(|(t, mid)| t <= mid));
+
+    let per_run = |half: &[&StatsRun], rule: &str| -> f64 {
+        if half.is_empty() {
+            return 0.0;
+        }
+        let total: u64 = half.iter().filter_map(|r| r.issues.get(rule)).sum();
+        round2(total as f64 / half.len() as f64)
+    };
+
+    let mut rules: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for run in &runs {
+        for (rule, count) in &run.issues {
+            let entry = rules.entry(rule).or_default();
+            entry.0 += 1;
+            entry.1 += count;
+        }
+    }
+    let rule_stats = || {
+        rules.iter().map(|(rule, (runs, total))| {
+            let first_half = per_run(&first, rule);
+            let second_half = per_run(&second, rule);
+            RuleStats {
+                rule: (*rule).to_string(),
+                runs: *runs,
+                total_count: *total,
+                first_half_per_run: first_half,
+                second_half_per_run: second_half,
+                trend: round2(second_half - first_half),
+            }
+        })
+    };
+
+    let mut top_rules: Vec<RuleStats> = rule_stats().collect();
+    top_rules.sort_by_key(|r| std::cmp::Reverse((r.runs, r.total_count)));
+    top_rules.truncate(args.top);
+
+    let mut trending_up: Vec<RuleStats> = rule_stats().filter(|r| r.trend > 0.0).collect();
+    trending_up.sort_by(|a, b| b.trend.total_cmp(&a.trend));
+    trending_up.truncate(args.top);
+
+    let mut trending_down: Vec<RuleStats> = rule_stats().filter(|r| r.trend < 0.0).collect();
+    trending_down.sort_by(|a, b| a.trend.total_cmp(&b.trend));
+    trending_down.truncate(args.top);
+
+    let mut stage_counts: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for run in runs.iter().filter(|r| !r.partial) {
+        for (stage, status) in &run.stages {
+            if matches!(status.as_str(), "ok" | "warn" | "fail") {
+                let entry = stage_counts.entry(stage).or_default();
+                entry.0 += 1;
+                entry.1 += u64::from(status == "fail");
+            }
+        }
+    }
+    let mut stages: Vec<StageStats> = stage_counts
+        .into_iter()
+        .map(|(stage, (runs, failed))| StageStats {
+            stage: stage.to_string(),
+            runs,
+            failed,
+            fail_rate: failed as f64 / runs as f64,
+        })
+        .collect();
+    stages.sort_by_key(|s| stage_rank(&s.stage));
+
+    StatsSummary {
+        runs: runs.len() as u64,
+        failed_runs: runs
+            .iter()
+            .filter(|r| !r.partial && r.status.as_deref() == Some("fail"))
+            .count() as u64,
+        partial_runs: runs.iter().filter(|r| r.partial).count() as u64,
+        from: from.map(format_utc),
+        to: to.map(format_utc),
+        top_rules,
+        trending_up,
+        trending_down,
+        stages,
+    }
+}
+
+/// Quotes a CSV field when needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// One table for spreadsheets; `section` tells rule rows from stage rows.
+fn stats_csv(summary: &StatsSummary) -> String {
+    let mut out = String::from(
+        "section,name,runs,total_count,first_half_per_run,second_half_per_run,trend,failed,fail_rate_percent\n",
+    );
+    for (section, rules) in [
+        ("top", &summary.top_rules),
+        ("trending_up", &summary.trending_up),
+        ("trending_down", &summary.trending_down),
+    ] {
+        for r in rules {
+            out.push_str(&format!(
+                "{section},{},{},{},{},{},{},,\n",
+                csv_field(&r.rule),
+                r.runs,
+                r.total_count,
+                r.first_half_per_run,
+                r.second_half_per_run,
+                r.trend
+            ));
+        }
+    }
+    for s in &summary.stages {
+        out.push_str(&format!(
+            "stage,{},{},,,,,{},{:.1}\n",
+            csv_field(&s.stage),
+            s.runs,
+            s.failed,
+            s.fail_rate * 100.0
+        ));
+    }
+    out
+}
+
+fn print_stats(summary: &StatsSummary) {
+    let partial = if summary.partial_runs > 0 {
+        format!("; {} partial, not in fail rates", summary.partial_runs)
+    } else {
+        String::new()
+    };
+    println!(
+        "Runs: {} ({} failed{partial}){}",
+        summary.runs,
+        summary.failed_runs,
+        summary
+            .from
+            .as_ref()
+            .zip(summary.to.as_ref())
+            .map(|(from, to)| format!(", {from} .. {to}"))
+            .unwrap_or_default()
+    );
+    for (title, rules) in [
+        ("Top recurring rules (runs, total)", &summary.top_rules),
+        (
+            "Trending up (per run, 1st -> 2nd half)",
+            &summary.trending_up,
+        ),
+        (
+            "Trending down (per run, 1st -> 2nd half)",
+            &summary.trending_down,
+        ),
+    ] {
+        println!("\n{title}:");
+        if rules.is_empty() {
+            println!("  (none)");
+        }
+        for r in rules {
+            println!(
+                "  {:>4} {:>6}  {:>6} -> {:<6}  {}",
+                r.runs, r.total_count, r.first_half_per_run, r.second_half_per_run, r.rule
+            );
+        }
+    }
+    println!("\nStage fail rate:");
+    if summary.stages.is_empty() {
+        println!("  (no stage data)");
+    }
+    for s in &summary.stages {
+        println!(
+            "  {:<15} {:>5.1}%  ({}/{})",
+            s.stage,
+            s.fail_rate * 100.0,
+            s.failed,
+            s.runs
+        );
+    }
+}
+
+fn run_stats(args: &StatsArgs, json_flag: bool) -> Result<()> {
+    let text = fs::read_to_string(&args.log)
+        .with_context(|| format!("Failed to read {}", args.log.display()))?;
+    let summary = summarize_stats(&parse_stats_log(&text), args);
+    match (args.format, json_flag) {
+        (StatsFormat::Json, _) | (_, true) => {
+            println!("{}", serde_json::to_string_pretty(&summary)?)
+        }
+        (StatsFormat::Csv, _) => print!("{}", stats_csv(&summary)),
+        (StatsFormat::Text, _) => print_stats(&summary),
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Report diff
+// =============================================================================
+
+/// The parts of a schema report `diff` compares; reports from other layers
+/// (`build.py`, `build.ts`) deserialize too.
+#[derive(Debug, Deserialize)]
+struct SavedReport {
+    #[serde(default)]
+    finished_at_utc: String,
+    #[serde(default)]
+    duration_ms: u64,
+    status: String,
+    #[serde(default)]
+    stages: Vec<SavedStage>,
+    #[serde(default)]
+    issues: Vec<SavedIssue>,
+    #[serde(default)]
+    metrics: Value,
+    /// Present for partial runs (`--changed-since` / `--staged`).
+    #[serde(default)]
+    scope: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedStage {
+    name: String,
+    status: String,
+    #[serde(default)]
+    duration_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedIssue {
+    language: String,
+    tool: String,
+    rule: String,
+    #[serde(default)]
+    count: u64,
+}
+
+impl SavedReport {
+    fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid report {}", path.display()))
+    }
+
+    fn coverage(&self) -> Option<f64> {
+        self.metrics.pointer("/coverage/lines_percent")?.as_f64()
+    }
+
+    /// `language: [tool] rule` -> count.
+    fn issue_counts(&self) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for issue in &self.issues {
+            *counts
+                .entry(format!(
+                    "{}: [{}] {}",
+                    issue.language, issue.tool, issue.rule
+                ))
+                .or_default() += issue.count;
+        }
+        counts
+    }
+
+    /// Stage -> (worst status, total duration); a stage may come from several tools.
+    fn stage_map(&self) -> BTreeMap<&str, (&str, u64)> {
+        let mut stages: BTreeMap<&str, (&str, u64)> = BTreeMap::new();
+        for stage in &self.stages {
+            let entry = stages.entry(&stage.name).or_insert((&stage.status, 0));
+            if status_severity(&stage.status) > status_severity(entry.0) {
+                entry.0 = &stage.status;
+            }
+            entry.1 += stage.duration_ms;
+        }
+        stages
+    }
+}
+
+/// `fail` > `warn` > `ok`/`cached` > `skip`/missing.
+fn status_severity(status: &str) -> u8 {
+    match status {
+        "fail" => 3,
+        "warn" => 2,
+        "ok" | "cached" => 1,
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct IssueChange {
+    issue: String,
+    old_count: u64,
+    new_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct StageChange {
+    stage: String,
+    old_status: Option<String>,
+    new_status: Option<String>,
+    regressed: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DurationChange {
+    stage: String,
+    old_ms: u64,
+    new_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportDiff {
+    old: String,
+    new: String,
+    /// `old` and/or `new` when that report is from a partial run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    partial: Vec<String>,
+    old_status: String,
+    new_status: String,
+    new_issues: Vec<IssueChange>,
+    resolved_issues: Vec<IssueChange>,
+    /// Issues present in both reports with a different count.
+    changed_issues: Vec<IssueChange>,
+    stage_changes: Vec<StageChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage_old: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage_new: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage_delta: Option<f64>,
+    duration_regressions: Vec<DurationChange>,
+    regressed: bool,
+}
+
+fn diff_reports(old: &SavedReport, new: &SavedReport, args: &DiffArgs) -> ReportDiff {
+    let old_issues = old.issue_counts();
+    let new_issues = new.issue_counts();
+    let mut new_list = Vec::new();
+    let mut changed = Vec::new();
+    for (issue, &new_count) in &new_issues {
+        let old_count = old_issues.get(issue).copied().unwrap_or(0);
+        let change = IssueChange {
+            issue: issue.clone(),
+            old_count,
+            new_count,
+        };
+        if old_count == 0 {
+            new_list.push(change);
+        } else if old_count != new_count {
+            changed.push(change);
+        }
+    }
+    let resolved: Vec<IssueChange> = old_issues
+        .iter()
+        .filter(|(issue, _)| !new_issues.contains_key(*issue))
+        .map(|(issue, &old_count)| IssueChange {
+            issue: issue.clone(),
+            old_count,
+            new_count: 0,
+        })
+        .collect();
+
+    let old_stages = old.stage_map();
+    let new_stages = new.stage_map();
+    let mut names: Vec<&str> = old_stages
+        .keys()
+        .chain(new_stages.keys())
+        .copied()
+        .collect();
+    names.sort_by_key(|name| stage_rank(name));
+    names.dedup();
+    let mut stage_changes = Vec::new();
+    let mut duration_regressions = Vec::new();
+    for name in names {
+        let old_stage = old_stages.get(name);
+        let new_stage = new_stages.get(name);
+        let old_status = old_stage.map(|s| s.0);
+        let new_status = new_stage.map(|s| s.0);
+        // * `ok` <-> `cached` is not a change worth reporting.
+        let old_rank = status_severity(old_status.unwrap_or("skip"));
+        let new_rank = status_severity(new_status.unwrap_or("skip"));
+        if old_rank != new_rank {
+            stage_changes.push(StageChange {
+                stage: name.to_string(),
+                old_status: old_status.map(str::to_string),
+                new_status: new_status.map(str::to_string),
+                regressed: new_rank > old_rank.max(1),
+            });
+        }
+        // * Only stages that really ran both times; `cached`/`skip` take ~0 ms.
+        let ran = |s: Option<&(&str, u64)>| {
+            s.filter(|(status, _)| matches!(*status, "ok" | "warn" | "fail"))
+                .map(|(_, ms)| *ms)
+        };
+        if let (Some(old_ms), Some(new_ms)) = (ran(old_stage), ran(new_stage)) {
+            let slower = new_ms.saturating_sub(old_ms);
+            if slower >= args.duration_min_ms
+                && slower as f64 > old_ms as f64 * args.duration_pct / 100.0
+            {
+                duration_regressions.push(DurationChange {
+                    stage: name.to_string(),
+                    old_ms,
+                    new_ms,
+                });
+            }
+        }
+    }
+
+    let coverage_old = old.coverage();
+    let coverage_new = new.coverage();
+    let coverage_delta = coverage_old
+        .zip(coverage_new)
+        .map(|(old, new)| round2(new - old));
+
+    let regressed = status_severity(&new.status) > status_severity(&old.status)
+        || !new_list.is_empty()
+        || changed.iter().any(|c| c.new_count > c.old_count)
+        || stage_changes.iter().any(|c| c.regressed)
+        || coverage_delta.is_some_and(|d| d < 0.0)
+        || !duration_regressions.is_empty();
+
+    ReportDiff {
+        old: old.finished_at_utc.clone(),
+        new: new.finished_at_utc.clone(),
+        partial: [("old", old), ("new", new)]
+            .into_iter()
+            .filter(|(_, report)| report.scope.is_some())
+            .map(|(side, _)| side.to_string())
+            .collect(),
+        old_status: old.status.clone(),
+        new_status: new.status.clone(),
+        new_issues: new_list,
+        resolved_issues: resolved,
+        changed_issues: changed,
+        stage_changes,
+        coverage_old,
+        coverage_new,
+        coverage_delta,
+        duration_regressions,
+        regressed,
+    }
+}
+
+fn print_diff(diff: &ReportDiff) {
+    if !diff.partial.is_empty() {
+        println!(
+            "WARNING: partial run ({}); checks it skipped show up as changes.\n",
+            diff.partial.join(", ")
+        );
+    }
+    println!(
+        "{} ({}) -> {} ({})",
+        diff.old, diff.old_status, diff.new, diff.new_status
+    );
+    for (title, issues) in [
+        ("New issues", &diff.new_issues),
+        ("Resolved issues", &diff.resolved_issues),
+        ("Changed issue counts", &diff.changed_issues),
+    ] {
+        if issues.is_empty() {
+            continue;
+        }
+        println!("\n{title}:");
+        for c in issues {
+            println!("  {:>4} -> {:<4} {}", c.old_count, c.new_count, c.issue);
+        }
+    }
+    if !diff.stage_changes.is_empty() {
+        println!("\nStage status changes:");
+        for c in &diff.stage_changes {
+            println!(
+                "  {} {:<15} {} -> {}",
+                if c.regressed { "!" } else { " " },
+                c.stage,
+                c.old_status.as_deref().unwrap_or("-"),
+                c.new_status.as_deref().unwrap_or("-")
+            );
+        }
+    }
+    if let (Some(old), Some(new), Some(delta)) =
+        (diff.coverage_old, diff.coverage_new, diff.coverage_delta)
+    {
+        println!("\nCoverage: {old:.2}% -> {new:.2}% ({delta:+.2})");
+    }
+    if !diff.duration_regressions.is_empty() {
+        println!("\nSlower stages:");
+        for c in &diff.duration_regressions {
+            println!(
+                "  {:<15} {:.2}s -> {:.2}s",
+                c.stage,
+                c.old_ms as f64 / 1000.0,
+                c.new_ms as f64 / 1000.0
+            );
+        }
+    }
+    println!(
+        "\n{}",
+        if diff.regressed {
+            "Regressed."
+        } else {
+            "No regressions."
+        }
+    );
+}
+
+fn run_diff(args: &DiffArgs, json_flag: bool) -> Result<()> {
+    // * Default baseline: the archived run before the latest one.
+    let old_path = resolve_report(args.old.as_deref().unwrap_or("1"))?;
+    let new_path = match args.new.as_deref() {
+        Some(arg) => resolve_report(arg)?,
+        None => PathBuf::from(REPORT_PATH),
+    };
+    let diff = diff_reports(
+        &SavedReport::load(&old_path)?,
+        &SavedReport::load(&new_path)?,
+        args,
+    );
+    // * A partial run leaves out crates and stages, which would read as resolved issues
+    //   and improved stages.
+    if !diff.partial.is_empty() && !args.allow_partial {
+        return Err(anyhow!(
+            "Not comparing: the {} report is from a partial run (--changed-since / --staged); \
+             compare two full runs or pass --allow-partial",
+            diff.partial.join(" and ")
+        ));
+    }
+    if json_flag {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print_diff(&diff);
+    }
+    if args.fail_on_regression && diff.regressed {
+        return Err(anyhow!("Regressions since {}", old_path.display()));
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Report history (.ci_cache/history)
+// =============================================================================
+
+/// Short commit hash of `HEAD` (`nogit` outside a repository).
+fn git_commit() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "nogit".to_string())
+}
+
+/// Splits an archived name into (timestamp, collision suffix, commit).
+fn history_name_parts(name: &str) -> (&str, u32, &str) {
+    let (stamp, commit) = name.split_once('_').unwrap_or((name, ""));
+    match stamp.split_once('-') {
+        Some((stamp, n)) => (stamp, n.parse().unwrap_or(0), commit),
+        None => (stamp, 0, commit),
+    }
+}
+
+fn history_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Archived report files, newest first.
+fn history_entries() -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(HISTORY_DIR)
+        .map(|dir| {
+            dir.flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                .collect()
+        })
+        .unwrap_or_default();
+    // * Names start with a sortable UTC timestamp; a collision suffix sorts after it.
+    entries.sort_by_cached_key(|p| {
+        let name = history_name(p);
+        let (stamp, n, _) = history_name_parts(&name);
+        (stamp.to_string(), n)
+    });
+    entries.reverse();
+    entries
+}
+
+/// Archives `json` as `<YYYYMMDDTHHMMSSmmmZ>[-N]_<commit>.json` and prunes to `max_count`.
+///
+/// * `-N` is added only when a run in the same millisecond already took the name.
+fn archive_report(json: &str, finished_at: &str, max_count: usize) -> Result<()> {
+    let stamp = DateTime::parse_from_rfc3339(finished_at)
+        .map(|ts| ts.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+        .format("%Y%m%dT%H%M%S%3fZ")
+        .to_string();
+    let commit = git_commit();
+    fs::create_dir_all(HISTORY_DIR)?;
+    let mut path = Path::new(HISTORY_DIR).join(format!("{stamp}_{commit}.json"));
+    for n in 2.. {
+        if !path.exists() {
+            break;
+        }
+        path = Path::new(HISTORY_DIR).join(format!("{stamp}-{n}_{commit}.json"));
+    }
+    fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))?;
+    for old in history_entries().iter().skip(max_count) {
+        fs::remove_file(old).with_context(|| format!("Failed to delete {}", old.display()))?;
+    }
+    Ok(())
+}
+
+/// Resolves a report argument, trying in order: an existing file, a list index
+/// (`0` = newest), a commit prefix, then a timestamp prefix of an archived name.
+///
+/// * `#3` always means index 3, even when a commit hash starts with the same digits.
+fn resolve_report(arg: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(arg);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let entries = history_entries();
+    let index = arg.strip_prefix('#').unwrap_or(arg).parse::<usize>();
+    if let Ok(index) = index {
+        if let Some(entry) = entries.get(index) {
+            return Ok(entry.clone());
+        }
+        if arg.starts_with('#') || arg.len() < 4 {
+            return Err(anyhow!(
+                "History entry {index} not found ({} archived in {HISTORY_DIR})",
+                entries.len()
+            ));
+        }
+    }
+    let names: Vec<String> = entries.iter().map(|p| history_name(p)).collect();
+    // * Commit first, then timestamp.
+    let parts: [fn(&str) -> &str; 2] = [
+        |name| history_name_parts(name).2,
+        |name| history_name_parts(name).0,
+    ];
+    for part in parts {
+        let matches: Vec<usize> = (0..entries.len())
+            .filter(|&i| part(&names[i]).starts_with(arg))
+            .collect();
+        match matches.as_slice() {
+            [] => continue,
+            [one] => return Ok(entries[*one].clone()),
+            _ => {
+                return Err(anyhow!(
+                    "`{arg}` matches {} history entries; use a longer prefix or `#<index>`",
+                    matches.len()
+                ))
+            }
+        }
+    }
+    Err(anyhow!("No report file or history entry matches `{arg}`"))
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryEntry {
+    index: usize,
+    name: String,
+    commit: String,
+    finished_at_utc: String,
+    status: String,
+    issues: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<f64>,
+    duration_ms: u64,
+    /// A `--changed-since` / `--staged` run, not a full one.
+    partial: bool,
+}
+
+fn run_history(command: &HistoryCommand, json_flag: bool) -> Result<()> {
+    match command {
+        HistoryCommand::List { limit } => {
+            let mut entries = Vec::new();
+            for (index, path) in history_entries().iter().take(*limit).enumerate() {
+                let name = history_name(path);
+                let report = match SavedReport::load(path) {
+                    Ok(report) => report,
+                    Err(err) => {
+                        eprintln!("Skipping {}: {err:#}", path.display());
+                        continue;
+                    }
+                };
+                entries.push(HistoryEntry {
+                    index,
+                    commit: history_name_parts(&name).2.to_string(),
+                    name,
+                    finished_at_utc: report.finished_at_utc.clone(),
+                    status: report.status.clone(),
+                    issues: report.issues.iter().map(|i| i.count).sum(),
+                    coverage: report.coverage(),
+                    duration_ms: report.duration_ms,
+                    partial: report.scope.is_some(),
+                });
+            }
+            if json_flag {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else if entries.is_empty() {
+                println!("No archived reports in {HISTORY_DIR}");
+            } else {
+                println!(
+                    "{:>3}  {:<26} {:<10} {:<6} {:>6} {:>8} {:>9}",
+                    "#", "finished (UTC)", "commit", "status", "issues", "coverage", "duration"
+                );
+                for e in &entries {
+                    println!(
+                        "{:>3}  {:<26} {:<10} {:<6} {:>6} {:>8} {:>8.1}s",
+                        e.index,
+                        e.finished_at_utc,
+                        e.commit,
+                        format!("{}{}", e.status, if e.partial { "*" } else { "" }),
+                        e.issues,
+                        e.coverage
+                            .map(|c| format!("{c:.2}%"))
+                            .unwrap_or_else(|| "-".to_string()),
+                        e.duration_ms as f64 / 1000.0
+                    );
+                }
+                if entries.iter().any(|e| e.partial) {
+                    println!("* partial run (--changed-since / --staged)");
+                }
+            }
+        }
+        HistoryCommand::Show { id } => {
+            let path = resolve_report(id)?;
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            print!("{text}");
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Tool runner
+// =============================================================================
+
+fn status_to_exit_code(status: ExitStatus) -> i32 {
+    // * `None` means terminated by signal on Unix, or otherwise unknown.
+    status.code().unwrap_or(1)
+}
+
+fn run_tool(
+    tool_name: &str,
+    cfg: &ToolConfig,
+    target_paths: &[String],
+    fix_mode: bool,
+    verbose: bool,
+    hb: &HeartbeatConfig,
+) -> ToolResult {
+    let started = Instant::now();
+
+    let mut cmd = Command::new(&cfg.command);
+
+    let args = if fix_mode && cfg.can_fix && !cfg.args_fix.is_empty() {
+        &cfg.args_fix
+    } else {
+        &cfg.args
+    };
+    cmd.args(args);
+
+    let mut command_line = format!("{} {}", cfg.command, args.join(" "));
+
+    // * `--path` reaches cargo as `-p <crate>` (see `scoped_config`), not as file arguments.
+    if verbose {
+        eprintln!("Running: {command_line}");
+        if !target_paths.is_empty() {
+            eprintln!("Target paths: {}", target_paths.join(", "));
+        }
+    }
+
+    let mut captured = run_with_heartbeat(tool_name, &mut cmd, hb);
+
+    // * A missing cargo subcommand exits with "no such command"; retry with the fallback.
+    let missing_subcommand = matches!(
+        &captured,
+        Ok(c) if c.exit_code != 0 && c.stderr.contains("no such command")
+    );
+    if missing_subcommand && !cfg.fallback_args.is_empty() {
+        if verbose {
+            eprintln!(
+                "Falling back to: {} {}",
+                cfg.command,
+                cfg.fallback_args.join(" ")
+            );
+        }
+        let mut fallback = Command::new(&cfg.command);
+        fallback.args(&cfg.fallback_args);
+        command_line = format!("{} {}", cfg.command, cfg.fallback_args.join(" "));
+        captured = run_with_heartbeat(tool_name, &mut fallback, hb);
+    }
+
+    let captured = match captured {
+        Ok(captured) => captured,
+        Err(err) => {
+            return ToolResult {
+                tool: tool_name.to_string(),
+                description: cfg.description.to_string(),
+                command: command_line,
+                available: false,
+                exit_code: 127,
+                stdout: String::new(),
+                stderr: format!("Failed to execute `{}`: {}", cfg.command, err),
+                critical: cfg.critical,
+                can_fix: cfg.can_fix,
+                fixed: fix_mode && cfg.can_fix,
+                hung: false,
+                timed_out: false,
+                duration_ms: started.elapsed().as_millis(),
+                log_path: None,
+                fixed_files: Vec::new(),
+            };
+        }
+    };
+
+    ToolResult {
+        tool: tool_name.to_string(),
+        description: cfg.description.to_string(),
+        command: command_line,
+        available: true,
+        exit_code: captured.exit_code,
+        stdout: captured.stdout,
+        stderr: captured.stderr,
+        critical: cfg.critical,
+        can_fix: cfg.can_fix,
+        fixed: fix_mode && cfg.can_fix,
+        hung: captured.hung,
+        timed_out: captured.timed_out,
+        duration_ms: started.elapsed().as_millis(),
+        log_path: None,
+        fixed_files: Vec::new(),
+    }
+}
+
+/// Writes the full output of a finished tool to `LOG_DIR`, then clamps it in `res`.
+///
+/// * Runs after parsing, so the log holds rendered diagnostics rather than raw JSON.
+fn write_tool_log(res: &mut ToolResult, clamp: usize) {
+    let finished = Utc::now();
+    let started = finished - chrono::Duration::milliseconds(res.duration_ms as i64);
+    let elapsed_secs = res.duration_ms as f64 / 1000.0;
+    let log = format!(
+        "# tool: {}\n# command: {}\n# started: {}\n\n--- stdout ---\n{}\n--- stderr ---\n{}\n\n# exit code: {}{}\n# elapsed: {:.2}s\n",
+        res.tool,
+        res.command,
+        format_utc(started),
+        res.stdout.trim_end(),
+        res.stderr.trim_end(),
+        res.exit_code,
+        if res.timed_out {
+            " (timed out)"
+        } else if res.hung {
+            " (hung)"
+        } else {
+            ""
+        },
+        elapsed_secs,
+    );
+
+    let path = Path::new(LOG_DIR).join(format!("{}_{}.log", res.tool, log_timestamp()));
+    let written = fs::create_dir_all(LOG_DIR).and_then(|_| fs::write(&path, log));
+    let path = path.to_string_lossy().replace('\\', "/");
+    if written.is_ok() {
+        res.log_path = Some(path);
+    }
+
+    res.stdout = clamp_output(&res.stdout, clamp, res.log_path.as_deref());
+    res.stderr = clamp_output(&res.stderr, clamp, res.log_path.as_deref());
+}
+
+/// Deletes all but the newest `max_count` logs in `log_dir` (`0` keeps all).
+///
+/// * Best effort, like writing them: a log that cannot be deleted is left alone.
+fn prune_logs(log_dir: &Path, max_count: usize) {
+    if max_count == 0 {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(log_dir) else {
+        return;
+    };
+    let mut logs: Vec<(std::time::SystemTime, PathBuf)> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|p| Some((fs::metadata(&p).ok()?.modified().ok()?, p)))
+        .collect();
+    logs.sort_by(|a, b| b.cmp(a));
+    for (_, old) in logs.iter().skip(max_count) {
+        let _ = fs::remove_file(old);
+    }
+}
+
+/// Keeps the head and tail of `text` within `limit` chars (0 = unlimited).
+fn clamp_output(text: &str, limit: usize, log_path: Option<&str>) -> String {
+    let total = text.chars().count();
+    if limit == 0 || total <= limit {
+        return text.to_string();
+    }
+    let head: String = text.chars().take(limit / 2).collect();
+    let tail: String = text.chars().skip(total - (limit - limit / 2)).collect();
+    let full = log_path
+        .map(|p| format!("; full output: {p}"))
+        .unwrap_or_default();
+    format!(
+        "{head}\n... [{} chars clamped{full}] ...\n{tail}",
+        total - limit
+    )
+}
+
+/// Read-only state shared by the scheduler and tool workers.
+struct RunContext<'a> {
+    cli: &'a Cli,
+    settings: &'a Settings,
+    profile: &'a Profile,
+    policy: StagePolicy,
+    hb: HeartbeatConfig,
+    target_paths: Vec<String>,
+    /// Versions found by the preflight; `self-check` reports these instead of probing again.
+    tool_versions: BTreeMap<String, String>,
+    /// Workspace members selected by `--path` or the changed files (empty = the whole workspace).
+    packages: Vec<String>,
+    /// Set by `--changed-since` / `--staged`.
+    scope: Option<RunScope>,
+    /// Changed Rust files per member for a file-scoped `fmt` (`None` = whole crates).
+    crate_files: Option<Vec<(WorkspaceMember, Vec<String>)>>,
+}
+
+/// A decided tool: its stage, plus the raw result and parsed output when it ran.
+type ToolSlot = (StageResult, Option<(ToolResult, ParsedOutput)>);
+
+fn skip_stage(cfg: &ToolConfig, tool_name: &str, note: String) -> StageResult {
+    StageResult {
+        name: cfg.stage.clone(),
+        status: StageStatus::Skip,
+        note: Some(note),
+        details: Some(json!({ "tool": tool_name })),
+        duration_ms: 0,
+    }
+}
+
+/// Cache key for a tool run, or `None` when the run must not be cached.
+///
+/// * Fix mode mutates the tree, so it never takes the cached path.
+/// * Partial runs (`--changed-since` / `--staged`) never read or write stamps.
+/// * `self-check` looks at PATH and installed tools, which no input hash covers.
+/// * Advisory checks cache only against a local advisory-db.
+/// * Layer tools hash only their own `cache_inputs` and never cache without them.
+fn tool_inputs_hash(ctx: &RunContext, cfg: &ToolConfig) -> Result<Option<String>> {
+    // * The `-p` flags of a `--path` run are part of the key, like any other argument.
+    let scoped = packages_config(ctx, cfg);
+    let cfg = scoped.as_ref().unwrap_or(cfg);
+    if ctx.cli.fix || ctx.scope.is_some() || cfg.builtin == Some(Builtin::SelfCheck) {
+        Ok(None)
+    } else if cfg.parser == OutputParser::Advisories
+        && ctx.settings.stage(&cfg.stage).advisory_db.is_none()
+    {
+        // * Fetched advisories change without any local input.
+        Ok(None)
+    } else if cfg.parser == OutputParser::LegacyJson {
+        if cfg.cache_inputs.is_empty() {
+            Ok(None)
+        } else {
+            compute_inputs_hash(cfg, &[], ctx.settings.include_untracked).map(Some)
+        }
+    } else {
+        compute_inputs_hash(
+            cfg,
+            &ctx.settings.target_dirs,
+            ctx.settings.include_untracked,
+        )
+        .map(Some)
+    }
+}
+
+// =============================================================================
+// Tool preflight (availability, versions, toolchain channel)
+// =============================================================================
+
+/// Cargo subcommands that ship with cargo itself; their version is cargo's.
+const CARGO_BUILTIN_SUBCOMMANDS: &[&str] = &[
+    "bench", "build", "check", "clean", "doc", "fetch", "metadata", "run", "test", "tree",
+];
+
+/// Leading numeric version in a `--version` line (`cargo 1.95.0 (...)` -> `[1, 95, 0]`).
+fn parse_version(text: &str) -> Option<Vec<u64>> {
+    let token = text
+        .split_whitespace()
+        .map(|t| t.trim_start_matches('v'))
+        .find(|t| t.starts_with(|c: char| c.is_ascii_digit()))?;
+    let core = token.split(['-', '+']).next()?;
+    core.split('.').map(|part| part.parse().ok()).collect()
+}
+
+fn version_below(found: &[u64], min: &[u64]) -> bool {
+    let len = found.len().max(min.len());
+    let pad = |v: &[u64]| {
+        (0..len)
+            .map(|i| v.get(i).copied().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    pad(found) < pad(min)
+}
+
+/// Rustup toolchain override (`cargo +nightly ...`) of a cargo command line.
+fn toolchain_override<'a>(command: &str, args: &'a [String]) -> Option<&'a str> {
+    args.first()
+        .filter(|_| command == "cargo")
+        .and_then(|a| a.strip_prefix('+'))
+}
+
+/// Probe name for a command: `cargo-<sub>` for external cargo subcommands.
+///
+/// * A `+toolchain` override before the subcommand is skipped.
+fn probe_name(command: &str, args: &[String]) -> String {
+    let skip = usize::from(toolchain_override(command, args).is_some());
+    match args.get(skip) {
+        Some(sub)
+            if command == "cargo"
+                && !sub.starts_with('-')
+                && !CARGO_BUILTIN_SUBCOMMANDS.contains(&sub.as_str()) =>
+        {
+            format!("cargo-{sub}")
+        }
+        _ => command.to_string(),
+    }
+}
+
+fn install_hint(probe: &str) -> String {
+    match probe {
+        "cargo" => "install Rust via https://rustup.rs".to_string(),
+        "cargo-fmt" => "rustup component add rustfmt".to_string(),
+        "cargo-clippy" => "rustup component add clippy".to_string(),
+        "cargo-llvm-cov" => {
+            "cargo install cargo-llvm-cov --locked && rustup component add llvm-tools-preview"
+                .to_string()
+        }
+        p if p.starts_with("cargo-") => format!("cargo install {p} --locked"),
+        p => format!("install `{p}` and make sure it is on PATH"),
+    }
+}
+
+/// Key of a probe in `tool_versions`: the probe name, plus the toolchain override.
+fn probe_key(probe: &str, toolchain: Option<&str>) -> String {
+    match toolchain {
+        Some(toolchain) => format!("{probe} +{toolchain}"),
+        None => probe.to_string(),
+    }
+}
+
+/// Version line of a probe, `Ok(None)` when available without a readable version,
+/// `Err(())` when not installed.
+///
+/// * Cargo probes run under the tool's `+toolchain`, as the tool itself will.
+fn probe_tool(
+    probe: &str,
+    command: &str,
+    toolchain: Option<&str>,
+    hb: &HeartbeatConfig,
+) -> Result<Option<String>, ()> {
+    if command != "cargo" {
+        let path = find_on_path(command).ok_or(())?;
+        return Ok(command_version(&path, hb));
+    }
+    let mut cmd = Command::new("cargo");
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    if let Some(sub) = probe.strip_prefix("cargo-") {
+        cmd.arg(sub);
+    }
+    cmd.arg("--version");
+    let captured = run_with_heartbeat(probe, &mut cmd, hb).map_err(|_| ())?;
+    if captured.exit_code != 0 {
+        return Err(());
+    }
+    Ok(captured
+        .stdout
+        .lines()
+        .chain(captured.stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string))
+}
+
+/// Outcome of the preflight.
+#[derive(Debug, Default)]
+struct Preflight {
+    /// Probe name (plus ` +toolchain` when overridden) -> version line.
+    versions: BTreeMap<String, String>,
+    /// Tool index -> stage status and note for tools that must not run.
+    blocked: BTreeMap<usize, (StageStatus, String)>,
+}
+
+/// Probes the commands of the tools about to run (each once) and checks
+/// `toolchain.min_versions` and `toolchain.channel`.
+///
+/// * Unavailable: critical tools fail, others are skipped; both with an install hint.
+/// * Too old / wrong channel: the tool fails (critical) or warns, without running.
+/// * A cargo subcommand with `fallback_args` is available if either one is.
+/// * The channel is checked per `+toolchain` override, from `cargo --version`.
+fn run_preflight(
+    ctx: &RunContext,
+    configs: &BTreeMap<String, ToolConfig>,
+    tools_to_run: &[String],
+    pending: &[usize],
+) -> Preflight {
+    let hb = HeartbeatConfig {
+        timeout_sec: PROBE_TIMEOUT_SEC,
+        ..ctx.hb
+    };
+    let toolchain = &ctx.settings.toolchain;
+    let mut preflight = Preflight::default();
+    let mut probed: BTreeMap<String, Result<Option<String>, ()>> = BTreeMap::new();
+    let mut probe = |name: &str, command: &str, toolchain: Option<&str>| {
+        probed
+            .entry(probe_key(name, toolchain))
+            .or_insert_with(|| probe_tool(name, command, toolchain, &hb))
+            .clone()
+    };
+    // * `cargo 1.96.0-nightly (...)`; a plain version is stable.
+    let channel_of = |line: &str| {
+        if line.contains("-nightly") {
+            "nightly"
+        } else if line.contains("-beta") {
+            "beta"
+        } else {
+            "stable"
+        }
+    };
+
+    for &idx in pending {
+        let tool_name = &tools_to_run[idx];
+        let cfg = &configs[tool_name];
+        if cfg.builtin.is_some() || cfg.command.is_empty() {
+            continue;
+        }
+        let name = probe_name(&cfg.command, &cfg.args);
+        let mut args = &cfg.args;
+        let mut found = probe(&name, &cfg.command, toolchain_override(&cfg.command, args))
+            .map(|v| (name.clone(), v));
+        if found.is_err() && !cfg.fallback_args.is_empty() {
+            args = &cfg.fallback_args;
+            let fallback = probe_name(&cfg.command, args);
+            found = probe(
+                &fallback,
+                &cfg.command,
+                toolchain_override(&cfg.command, args),
+            )
+            .map(|v| (fallback, v));
+        }
+        let problem = match found {
+            Err(()) => {
+                let status = if cfg.critical {
+                    StageStatus::Fail
+                } else {
+                    StageStatus::Skip
+                };
+                Some((
+                    status,
+                    format!("`{name}` is not available ({})", install_hint(&name)),
+                ))
+            }
+            Ok((found_name, version)) => {
+                let min = toolchain.min_versions.get(&found_name);
+                let parsed = version.as_deref().and_then(parse_version);
+                let too_old = match (min, &parsed) {
+                    (Some(min), Some(v)) => {
+                        parse_version(min).is_some_and(|min| version_below(v, &min))
+                    }
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                let severity = if cfg.critical {
+                    StageStatus::Fail
+                } else {
+                    StageStatus::Warn
+                };
+                if too_old {
+                    Some((
+                        severity,
+                        format!(
+                            "`{found_name}` {} is below the required {} ({})",
+                            parsed
+                                .map(|v| v.iter().map(u64::to_string).collect::<Vec<_>>().join("."))
+                                .unwrap_or_else(|| "(unknown version)".to_string()),
+                            min.map(String::as_str).unwrap_or_default(),
+                            install_hint(&found_name)
+                        ),
+                    ))
+                } else if cfg.command == "cargo" {
+                    let cargo = probe("cargo", "cargo", toolchain_override("cargo", args));
+                    match (&toolchain.channel, cargo.ok().flatten()) {
+                        (Some(required), Some(line)) if channel_of(&line) != required => Some((
+                            severity,
+                            format!(
+                                "Toolchain channel is `{}`, config requires `{required}` (rustup override set {required})",
+                                channel_of(&line)
+                            ),
+                        )),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(problem) = problem {
+            preflight.blocked.insert(idx, problem);
+        }
+    }
+
+    for (name, result) in probed {
+        if let Ok(Some(version)) = result {
+            preflight.versions.insert(name, version);
+        }
+    }
+    if ctx.cli.verbose {
+        for (name, version) in &preflight.versions {
+            eprintln!("Preflight: {name}: {version}");
+        }
+    }
+    preflight
+}
+
+// =============================================================================
+// Workspace (cargo metadata)
+// =============================================================================
+
+/// Cargo subcommands that run once per crate with `-p <crate>`.
+const PACKAGE_SCOPED_SUBCOMMANDS: &[&str] = &["fmt", "clippy", "check", "test"];
+
+/// Cargo subcommands that run once with `-p` for every selected crate, so that coverage
+/// stays a single percentage (over the selected crates).
+const PACKAGE_SET_SUBCOMMANDS: &[&str] = &["llvm-cov", "tarpaulin"];
+
+/// Files (relative to the workspace root) whose change affects every member.
+const WORKSPACE_WIDE_FILES: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    "rust-toolchain",
+    "rust-toolchain.toml",
+    "clippy.toml",
+    ".clippy.toml",
+    "rustfmt.toml",
+    ".rustfmt.toml",
+    ".cargo/config",
+    ".cargo/config.toml",
+];
+
+/// A workspace member from `cargo metadata`.
+#[derive(Debug, Clone)]
+struct WorkspaceMember {
+    name: String,
+    /// Absolute manifest directory.
+    dir: PathBuf,
+    edition: String,
+    /// Other members this one depends on (any dependency kind).
+    deps: BTreeSet<String>,
+}
+
+#[derive(Debug, Default)]
+struct Workspace {
+    root: PathBuf,
+    members: Vec<WorkspaceMember>,
+}
+
+impl Workspace {
+    /// Reads `cargo metadata --no-deps`; `None` outside a cargo project.
+    fn load() -> Option<Workspace> {
+        let out = Command::new("cargo")
+            .args(["metadata", "--no-deps", "--format-version", "1"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|out| out.status.success())?;
+        let metadata: Value = serde_json::from_slice(&out.stdout).ok()?;
+        let packages = metadata.get("packages")?.as_array()?;
+        let names: BTreeSet<&str> = packages
+            .iter()
+            .filter_map(|p| p.get("name").and_then(Value::as_str))
+            .collect();
+        let members = packages
+            .iter()
+            .filter_map(|p| {
+                let name = p.get("name")?.as_str()?;
+                let manifest = Path::new(p.get("manifest_path")?.as_str()?);
+                let deps = p
+                    .get("dependencies")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|d| d.get("name").and_then(Value::as_str))
+                    .filter(|dep| *dep != name && names.contains(dep))
+                    .map(str::to_string)
+                    .collect();
+                Some(WorkspaceMember {
+                    name: name.to_string(),
+                    dir: manifest.parent()?.to_path_buf(),
+                    edition: p
+                        .get("edition")
+                        .and_then(Value::as_str)
+                        .unwrap_or("2021")
+                        .to_string(),
+                    deps,
+                })
+            })
+            .collect();
+        Some(Workspace {
+            root: PathBuf::from(metadata.get("workspace_root")?.as_str()?),
+            members,
+        })
+    }
+
+    /// Members touched by `paths` (files or directories, relative to the current directory).
+    ///
+    /// * A path inside a member selects the innermost one; a directory selects every
+    ///   member below it.
+    fn members_for_paths(&self, paths: &[String]) -> BTreeSet<String> {
+        let mut selected = BTreeSet::new();
+        for path in paths {
+            let path = absolute_path(path);
+            let below: Vec<&WorkspaceMember> = self
+                .members
+                .iter()
+                .filter(|m| m.dir.starts_with(&path))
+                .collect();
+            if below.is_empty() {
+                selected.extend(self.member_of(&path).map(|m| m.name.clone()));
+            } else {
+                selected.extend(below.into_iter().map(|m| m.name.clone()));
+            }
+        }
+        selected
+    }
+
+    /// Innermost member containing the absolute `path`.
+    fn member_of(&self, path: &Path) -> Option<&WorkspaceMember> {
+        self.members
+            .iter()
+            .filter(|m| path.starts_with(&m.dir))
+            .max_by_key(|m| m.dir.components().count())
+    }
+
+    /// `selected` plus every member depending on one of them, transitively.
+    fn with_dependents(&self, selected: BTreeSet<String>) -> BTreeSet<String> {
+        let mut all = selected;
+        loop {
+            let before = all.len();
+            for member in &self.members {
+                if member.deps.iter().any(|dep| all.contains(dep)) {
+                    all.insert(member.name.clone());
+                }
+            }
+            if all.len() == before {
+                return all;
+            }
+        }
+    }
+
+    /// Whether a changed file (root manifest, lockfile, toolchain or lint config) affects
+    /// every member.
+    fn is_workspace_wide(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root).is_ok_and(|rel| {
+            let rel = rel.to_string_lossy().replace('\\', "/");
+            WORKSPACE_WIDE_FILES.contains(&rel.as_str())
+        })
+    }
+}
+
+/// Files changed since the merge base with `base` (committed, staged, unstaged and
+/// untracked), or only the staged files when `base` is `None`.
+///
+/// * Paths are relative to the current directory; deleted files are included so their
+///   crates still count as changed.
+/// * Submodules and `EXCLUDED_DIRS` are dropped, as in `discover_files`.
+fn git_changed_files(base: Option<&str>) -> Result<Vec<String>> {
+    let git = |args: &[&str]| -> Result<Vec<String>> {
+        let out = Command::new("git")
+            .args(args)
+            .output()
+            .context("Failed to run git")?;
+        if !out.status.success() {
+            return Err(anyhow!(
+                "`git {}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout)
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect())
+    };
+    let mut files: BTreeSet<String> = BTreeSet::new();
+    match base {
+        Some(base) => {
+            files.extend(git(&[
+                "diff",
+                "--name-only",
+                "-z",
+                "--relative",
+                "--merge-base",
+                base,
+                "--",
+            ])?);
+            files.extend(git(&["ls-files", "-z", "--others", "--exclude-standard"])?);
+        }
+        None => files.extend(git(&[
+            "diff",
+            "--name-only",
+            "-z",
+            "--relative",
+            "--cached",
+        ])?),
+    }
+    let submodules = submodule_paths();
+    Ok(files
+        .into_iter()
+        .filter(|file| !is_excluded(Path::new(file), &submodules))
+        .collect())
+}
+
+/// Scope of a partial run (`--changed-since` / `--staged`), recorded in the report.
+#[derive(Debug, Clone, Serialize)]
+struct RunScope {
+    /// `changed-since` or `staged`.
+    mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
+    /// Changed files, relative to the working directory.
+    files: Vec<String>,
+    /// Crates checked by fmt/clippy/check/test: changed crates plus reverse dependencies.
+    crates: Vec<String>,
+}
+
+impl RunScope {
+    fn describe(&self) -> String {
+        let what = match self.base {
+            Some(ref base) => format!("changed since `{base}`"),
+            None => "staged".to_string(),
+        };
+        let crates = if self.crates.is_empty() {
+            "no crates".to_string()
+        } else {
+            format!("crates: {}", self.crates.join(", "))
+        };
+        format!("{} file(s) {what}; {crates}", self.files.len())
+    }
+}
+
+/// `path` made absolute against the (canonical) current directory, with `.`/`..` resolved
+/// lexically so that deleted files still map to their crate.
+fn absolute_path(path: &str) -> PathBuf {
+    let cwd = fs::canonicalize(".").unwrap_or_default();
+    let mut out = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+fn cargo_subcommand_in(cfg: &ToolConfig, subcommands: &[&str]) -> bool {
+    cfg.builtin.is_none()
+        && cfg.command == "cargo"
+        && cfg
+            .args
+            .first()
+            .is_some_and(|sub| subcommands.contains(&sub.as_str()))
+}
+
+/// Whether `cfg` is a cargo subcommand that runs once per crate with `-p <crate>`.
+fn package_scoped(cfg: &ToolConfig) -> bool {
+    cargo_subcommand_in(cfg, PACKAGE_SCOPED_SUBCOMMANDS)
+}
+
+/// `cfg` narrowed to the crates selected by `--path`, or `None` when it runs as configured.
+///
+/// * Per-crate tools get every selected `-p` here; `run_per_crate` splits them per run.
+fn packages_config(ctx: &RunContext, cfg: &ToolConfig) -> Option<ToolConfig> {
+    let narrowed = package_scoped(cfg) || cargo_subcommand_in(cfg, PACKAGE_SET_SUBCOMMANDS);
+    (!ctx.packages.is_empty() && narrowed).then(|| scoped_config(cfg, &ctx.packages))
+}
+
+/// `args` with `--all`/`--workspace` replaced by `-p <crate>` flags (before any `--`).
+fn with_packages(args: &[String], packages: &[String]) -> Vec<String> {
+    if args.is_empty() {
+        return Vec::new();
+    }
+    let split = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let mut out: Vec<String> = args[..split]
+        .iter()
+        .filter(|a| !matches!(a.as_str(), "--all" | "--workspace"))
+        .cloned()
+        .collect();
+    for package in packages {
+        out.push("-p".to_string());
+        out.push(package.clone());
+    }
+    out.extend_from_slice(&args[split..]);
+    out
+}
+
+/// One run per crate for a package-scoped tool: `-p <crate>` for each selected crate, or
+/// `rustfmt` on each crate's changed files when the run is scoped to changed files.
+fn crate_runs(ctx: &RunContext, cfg: &ToolConfig) -> Vec<(String, ToolConfig)> {
+    let is_fmt = cfg.args.first().is_some_and(|sub| sub == "fmt");
+    if let (Some(crate_files), true) = (&ctx.crate_files, is_fmt) {
+        return crate_files
+            .iter()
+            .map(|(member, files)| {
+                let edition = ["--edition".to_string(), member.edition.clone()];
+                let cfg = ToolConfig {
+                    command: "rustfmt".to_string(),
+                    args: [&edition[..], &["--check".to_string()], files].concat(),
+                    args_fix: [&edition[..], files].concat(),
+                    fallback_args: Vec::new(),
+                    ..cfg.clone()
+                };
+                (member.name.clone(), cfg)
+            })
+            .collect();
+    }
+    ctx.packages
+        .iter()
+        .map(|package| {
+            (
+                package.clone(),
+                scoped_config(cfg, std::slice::from_ref(package)),
+            )
+        })
+        .collect()
+}
+
+fn scoped_config(cfg: &ToolConfig, packages: &[String]) -> ToolConfig {
+    ToolConfig {
+        args: with_packages(&cfg.args, packages),
+        args_fix: with_packages(&cfg.args_fix, packages),
+        fallback_args: with_packages(&cfg.fallback_args, packages),
+        ..cfg.clone()
+    }
+}
+
+/// Runs a package-scoped tool once per selected crate and merges the results.
+///
+/// * Each crate gets its own status and note in `details.crates`; the merged exit code
+///   is the first nonzero one, so the stage takes the worst crate.
+fn run_per_crate(
+    ctx: &RunContext,
+    tool_name: &str,
+    cfg: &ToolConfig,
+    runs: &[(String, ToolConfig)],
+    hb: &HeartbeatConfig,
+) -> (ToolResult, ParsedOutput) {
+    let mut merged: Option<ToolResult> = None;
+    let mut parsed = ParsedOutput::default();
+    let mut crates: Vec<Value> = Vec::new();
+    let mut notes: Vec<String> = Vec::new();
+    let mut issues: BTreeMap<(String, String), Issue> = BTreeMap::new();
+
+    for (package, scoped) in runs {
+        let mut res = run_tool(
+            tool_name,
+            scoped,
+            &ctx.target_paths,
+            false,
+            ctx.cli.verbose,
+            hb,
+        );
+        let crate_parsed = parse_output(cfg, &mut res, &ctx.policy);
+        let stage = stage_from_result(cfg, &res, &crate_parsed);
+
+        let mut entry = json!({
+            "crate": package,
+            "status": stage.status,
+            "command": res.command,
+            "exit_code": res.exit_code,
+            "duration_ms": res.duration_ms,
+        });
+        if let Some(obj) = entry.as_object_mut() {
+            if let Some(ref note) = stage.note {
+                obj.insert("note".to_string(), json!(note));
+            }
+            obj.extend(crate_parsed.details);
+        }
+        crates.push(entry);
+        if stage.status != StageStatus::Ok {
+            notes.push(format!(
+                "{package}: {}",
+                stage.note.as_deref().unwrap_or(stage.status.as_str())
+            ));
+        }
+
+        parsed.warnings += crate_parsed.warnings;
+        for issue in crate_parsed.issues {
+            let count = issue.count;
+            issues
+                .entry((issue.tool.clone(), issue.rule.clone()))
+                .and_modify(|known| known.count += count)
+                .or_insert(issue);
+        }
+        if let Some(counts) = crate_parsed.test_counts {
+            let total = parsed.test_counts.get_or_insert_with(TestCounts::default);
+            total.total += counts.total;
+            total.passed += counts.passed;
+            total.failed += counts.failed;
+            total.skipped += counts.skipped;
+        }
+
+        let header = format!("=== {package} ===\n");
+        merged = Some(match merged {
+            None => ToolResult {
+                stdout: format!("{header}{}", res.stdout),
+                stderr: format!("{header}{}", res.stderr),
+                ..res
+            },
+            Some(mut all) => {
+                all.command = format!("{}; {}", all.command, res.command);
+                all.available &= res.available;
+                if all.exit_code == 0 {
+                    all.exit_code = res.exit_code;
+                }
+                all.hung |= res.hung;
+                all.timed_out |= res.timed_out;
+                all.duration_ms += res.duration_ms;
+                all.stdout = format!("{}\n{header}{}", all.stdout, res.stdout);
+                all.stderr = format!("{}\n{header}{}", all.stderr, res.stderr);
+                all
+            }
+        });
+    }
+
+    parsed.issues = issues.into_values().collect();
+    parsed.note = Some(if notes.is_empty() {
+        format!("{} crate(s) ok", runs.len())
+    } else {
+        notes.join("; ")
+    });
+    if let Some(counts) = parsed.test_counts {
+        parsed
+            .details
+            .insert("test_counts".to_string(), json!(counts));
+    }
+    parsed.details.insert("crates".to_string(), json!(crates));
+    let res = merged.unwrap_or_else(|| {
+        run_tool(
+            tool_name,
+            cfg,
+            &ctx.target_paths,
+            false,
+            ctx.cli.verbose,
+            hb,
+        )
+    });
+    (res, parsed)
+}
+
+// =============================================================================
+// Self-check (config schema, commands, target dirs, CI scripts)
+// =============================================================================
+
+/// Validates `value` against the JSON Schema subset used by `schema/ci_config.schema.json`
+/// (`type`, `enum`, `properties`, `additionalProperties`, `required`, `items`,
+/// `minimum`/`maximum`, `minLength`, `minItems`, `minProperties`, local `$ref`).
+fn validate_json(
+    value: &Value,
+    schema: &Value,
+    root: &Value,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    let at = if path.is_empty() { "(root)" } else { path };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match reference.strip_prefix('#').and_then(|p| root.pointer(p)) {
+            Some(target) => validate_json(value, target, root, path, errors),
+            None => errors.push(format!("{at}: unresolved schema $ref `{reference}`")),
+        }
+        return;
+    }
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let type_matches = |t: &&str| match *t {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    };
+    if !types.is_empty() && !types.iter().any(type_matches) {
+        errors.push(format!("{at}: expected {}", types.join(" or ")));
+        return;
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            let options: Vec<String> = options.iter().map(Value::to_string).collect();
+            errors.push(format!("{at}: must be one of {}", options.join(", ")));
+        }
+    }
+    let limit = |key: &str| schema.get(key).and_then(Value::as_f64);
+    if let Some(n) = value.as_f64() {
+        if limit("minimum").is_some_and(|min| n < min)
+            || limit("maximum").is_some_and(|max| n > max)
+        {
+            errors.push(format!("{at}: {n} is out of range"));
+        }
+    }
+    if let (Some(text), Some(min)) = (value.as_str(), limit("minLength")) {
+        if (text.chars().count() as f64) < min {
+            errors.push(format!("{at}: must not be empty"));
+        }
+    }
+    if let Some(items) = value.as_array() {
+        if limit("minItems").is_some_and(|min| (items.len() as f64) < min) {
+            errors.push(format!("{at}: must not be empty"));
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                validate_json(item, item_schema, root, &format!("{path}[{i}]"), errors);
+            }
+        }
+    }
+    if let Some(obj) = value.as_object() {
+        if limit("minProperties").is_some_and(|min| (obj.len() as f64) < min) {
+            errors.push(format!("{at}: must not be empty"));
+        }
+        for key in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !obj.contains_key(key) {
+                errors.push(format!("{at}: missing `{key}`"));
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, child) in obj {
+            let child_path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}.{key}")
+            };
+            match (
+                properties.and_then(|p| p.get(key)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(child_schema), _) => {
+                    validate_json(child, child_schema, root, &child_path, errors)
+                }
+                (None, Some(Value::Bool(false))) => {
+                    errors.push(format!("{child_path}: unknown key"))
+                }
+                (None, Some(extra @ Value::Object(_))) => {
+                    validate_json(child, extra, root, &child_path, errors)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Resolves a command the way `Command::new` would: a path as-is, otherwise a PATH lookup.
+fn find_on_path(command: &str) -> Option<PathBuf> {
+    let with_exts = |base: PathBuf| -> Option<PathBuf> {
+        if base.is_file() {
+            return Some(base);
+        }
+        if cfg!(windows) {
+            let exts = std::env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.CMD;.BAT".to_string());
+            for ext in exts.split(';').filter(|e| !e.is_empty()) {
+                let candidate = base.with_extension(ext.trim_start_matches('.'));
+                if candidate.is_file() {
+                    return Some(candidate);
+                }
+            }
+        }
+        None
+    };
+    if command.contains('/') || command.contains('\\') {
+        return with_exts(PathBuf::from(command));
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?).find_map(|dir| with_exts(dir.join(command)))
+}
+
+/// First line of `<command> --version` (stdout, else stderr).
+fn command_version(path: &Path, hb: &HeartbeatConfig) -> Option<String> {
+    let mut cmd = Command::new(path);
+    cmd.arg("--version");
+    let captured = run_with_heartbeat(&path.to_string_lossy(), &mut cmd, hb).ok()?;
+    if captured.exit_code != 0 {
+        return None;
+    }
+    captured
+        .stdout
+        .lines()
+        .chain(captured.stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// CI scripts next to the runner (`CI_SCRIPT_DIRS`), split into shell and PowerShell.
+///
+/// * Found via `discover_files`, so ignored files and submodules are never linted.
+fn ci_scripts(untracked: bool) -> (Vec<String>, Vec<String>) {
+    let mut shell = BTreeSet::new();
+    let mut powershell = BTreeSet::new();
+    let dirs: Vec<String> = CI_SCRIPT_DIRS
+        .iter()
+        .filter(|dir| Path::new(dir).is_dir())
+        .map(|dir| (*dir).to_string())
+        .collect();
+    let script_dirs: Vec<PathBuf> = dirs.iter().map(|d| normalize_path(Path::new(d))).collect();
+    for path in discover_files(&dirs, untracked).unwrap_or_default() {
+        // * Only scripts directly in a CI script dir, not in its subdirectories.
+        if !path
+            .parent()
+            .is_some_and(|parent| script_dirs.iter().any(|dir| dir == parent))
+        {
+            continue;
+        }
+        let name = path.to_string_lossy().replace('\\', "/");
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("sh" | "bash") => shell.insert(name),
+            Some("ps1" | "psm1") => powershell.insert(name),
+            _ => false,
+        };
+    }
+    (
+        shell.into_iter().collect(),
+        powershell.into_iter().collect(),
+    )
+}
+
+/// One finding of a CI script linter; `error` fails the self-check, others warn.
+struct ScriptFinding {
+    tool: &'static str,
+    file: String,
+    line: u64,
+    rule: String,
+    error: bool,
+    message: String,
+}
+
+/// `shellcheck -f json1` on all shell scripts (warnings and errors only).
+fn run_shellcheck(
+    path: &Path,
+    scripts: &[String],
+    hb: &HeartbeatConfig,
+) -> Result<Vec<ScriptFinding>> {
+    let mut cmd = Command::new(path);
+    cmd.args(["-f", "json1", "-S", "warning"]).args(scripts);
+    let captured = run_with_heartbeat("shellcheck", &mut cmd, hb)?;
+    let report: Value = serde_json::from_str(captured.stdout.trim())
+        .with_context(|| format!("shellcheck printed no JSON: {}", captured.stderr.trim()))?;
+    let text = |c: &Value, key: &str| {
+        c.get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    Ok(report
+        .get("comments")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|c| ScriptFinding {
+            tool: "shellcheck",
+            file: text(c, "file"),
+            line: c.get("line").and_then(Value::as_u64).unwrap_or(0),
+            rule: format!("SC{}", c.get("code").and_then(Value::as_u64).unwrap_or(0)),
+            error: text(c, "level") == "error",
+            message: text(c, "message"),
+        })
+        .collect())
+}
+
+/// PowerShell parser diagnostics plus PSScriptAnalyzer (when the module is installed).
+///
+/// * Returns whether PSScriptAnalyzer was available.
+fn run_psscriptanalyzer(
+    pwsh: &Path,
+    scripts: &[String],
+    hb: &HeartbeatConfig,
+) -> Result<(bool, Vec<ScriptFinding>)> {
+    let files: Vec<String> = scripts
+        .iter()
+        .map(|s| format!("'{}'", s.replace('\'', "''")))
+        .collect();
+    let script = format!(
+        r#"$ErrorActionPreference = 'Stop'
+$hasPssa = [bool](Get-Module -ListAvailable -Name PSScriptAnalyzer)
+$found = foreach ($f in @({})) {{
+  $errs = $null
+  [void][System.Management.Automation.Language.Parser]::ParseFile((Resolve-Path $f), [ref]$null, [ref]$errs)
+  foreach ($e in $errs) {{ [pscustomobject]@{{ file = $f; line = $e.Extent.StartLineNumber; rule = 'ParseError'; severity = 'ParseError'; message = $e.Message }} }}
+  if ($hasPssa) {{ Invoke-ScriptAnalyzer -Path $f | ForEach-Object {{ [pscustomobject]@{{ file = $f; line = $_.Line; rule = $_.RuleName; severity = "$($_.Severity)"; message = $_.Message }} }} }}
+}}
+[pscustomobject]@{{ pssa = $hasPssa; findings = @($found) }} | ConvertTo-Json -Depth 4 -Compress"#,
+        files.join(", ")
+    );
+    let mut cmd = Command::new(pwsh);
+    cmd.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+    let captured = run_with_heartbeat("PSScriptAnalyzer", &mut cmd, hb)?;
+    let report: Value = serde_json::from_str(captured.stdout.trim())
+        .with_context(|| format!("PowerShell printed no JSON: {}", captured.stderr.trim()))?;
+    let text = |c: &Value, key: &str| {
+        c.get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let findings = report
+        .get("findings")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|f| text(f, "severity") != "Information")
+        .map(|f| ScriptFinding {
+            tool: "psscriptanalyzer",
+            file: text(f, "file"),
+            line: f.get("line").and_then(Value::as_u64).unwrap_or(0),
+            rule: text(f, "rule"),
+            error: matches!(text(f, "severity").as_str(), "Error" | "ParseError"),
+            message: text(f, "message"),
+        })
+        .collect();
+    Ok((
+        report.get("pssa").and_then(Value::as_bool) == Some(true),
+        findings,
+    ))
+}
+
+/// Validates `config` against its local `$schema`, or the first of `CONFIG_SCHEMA_PATHS`.
+fn check_config_schema(
+    config_path: &Path,
+    config: &Value,
+    out: &mut String,
+    errors: &mut Vec<(String, String)>,
+) {
+    let local_schema = config
+        .get("$schema")
+        .and_then(Value::as_str)
+        .filter(|s| !s.contains("://"))
+        .map(|s| config_path.parent().unwrap_or(Path::new(".")).join(s));
+    let schema_path = local_schema.or_else(|| {
+        CONFIG_SCHEMA_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.is_file())
+    });
+    let schema = schema_path
+        .as_ref()
+        .and_then(|p| fs::read_to_string(p).ok())
+        .and_then(|t| serde_json::from_str::<Value>(&t).ok());
+    match (schema_path, schema) {
+        (Some(path), Some(schema)) => {
+            let mut violations = Vec::new();
+            validate_json(config, &schema, &schema, "", &mut violations);
+            out.push_str(&format!(
+                "config: {} against {} ({} violations)\n",
+                config_path.display(),
+                path.display(),
+                violations.len()
+            ));
+            errors.extend(
+                violations
+                    .into_iter()
+                    .map(|v| ("config-schema".to_string(), v)),
+            );
+        }
+        (Some(path), None) => errors.push((
+            "config-schema".to_string(),
+            format!("Schema {} is missing or not valid JSON", path.display()),
+        )),
+        (None, _) => out.push_str(&format!(
+            "config: {} (no schema found; typed validation only)\n",
+            config_path.display()
+        )),
+    }
+}
+
+/// Validates the CI layer itself before any real stage runs.
+///
+/// * Fails on: a config that does not parse or load, schema violations, missing
+///   commands of critical tools, missing target dirs, script linter errors. Missing
+///   commands of non-critical tools and linter warnings only warn; missing linters
+///   are noted.
+fn run_self_check(
+    ctx: &RunContext,
+    tool_name: &str,
+    cfg: &ToolConfig,
+) -> (ToolResult, ParsedOutput) {
+    let started = Instant::now();
+    let mut parsed = ParsedOutput::default();
+    let mut errors: Vec<(String, String)> = Vec::new();
+    let mut warnings: Vec<(String, String)> = Vec::new();
+    let mut out = String::new();
+    // * Short timeout: a `--version` probe or a linter must not stall the whole run.
+    let hb = HeartbeatConfig {
+        timeout_sec: PROBE_TIMEOUT_SEC,
+        ..ctx.hb
+    };
+
+    // Config: JSON syntax, then the schema, then the typed load.
+    let config_path = ctx
+        .cli
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(CONFIG_PATH));
+    if let Ok(text) = fs::read_to_string(&config_path) {
+        match serde_json::from_str::<Value>(&text) {
+            Ok(config) => check_config_schema(&config_path, &config, &mut out, &mut errors),
+            Err(err) => errors.push((
+                "config-parse".to_string(),
+                format!("{}: {err}", config_path.display()),
+            )),
+        }
+    } else if ctx.cli.config.is_none() {
+        out.push_str("config: none (built-in defaults)\n");
+    }
+    // * Schema violations already explain a failed typed load; anything else (unknown
+    //   tool fields, layer clashes, a missing `--config`) is reported from here.
+    if errors.is_empty() {
+        if let Err(err) = load_settings(ctx.cli.config.as_deref()) {
+            errors.push(("config-invalid".to_string(), format!("{err:#}")));
+        }
+    }
+
+    // Commands on PATH, with the versions the preflight found.
+    let mut commands: BTreeMap<&str, (bool, Vec<&str>)> = BTreeMap::new();
+    let layer_tools = ctx.settings.layer_tools();
+    for (name, tool) in ctx.settings.tools.iter().chain(&layer_tools) {
+        if tool.builtin.is_none() && !tool.command.is_empty() {
+            let entry = commands.entry(tool.command.as_str()).or_default();
+            entry.0 |= tool.critical;
+            entry.1.push(name.as_str());
+        }
+    }
+    let mut command_details = Map::new();
+    for (command, (critical, tools)) in &commands {
+        match find_on_path(command) {
+            Some(path) => {
+                // * Versions live in the report's `tool_versions`, not in the details.
+                out.push_str(&format!(
+                    "command: {command} -> {} ({})\n",
+                    path.display(),
+                    ctx.tool_versions
+                        .get(*command)
+                        .map_or("version not probed", String::as_str)
+                ));
+                command_details.insert(
+                    (*command).to_string(),
+                    json!({ "path": path.to_string_lossy(), "tools": tools }),
+                );
+            }
+            None => {
+                let finding = (
+                    "command-missing".to_string(),
+                    format!(
+                        "`{command}` not found on PATH (used by {})",
+                        tools.join(", ")
+                    ),
+                );
+                if *critical {
+                    errors.push(finding);
+                } else {
+                    warnings.push(finding);
+                }
+                command_details.insert(
+                    (*command).to_string(),
+                    json!({ "path": null, "tools": tools }),
+                );
+            }
+        }
+    }
+    parsed
+        .details
+        .insert("commands".to_string(), Value::Object(command_details));
+
+    // Target dirs: configured ones must all exist; of the built-in defaults, at least one.
+    let explicit = !ctx.cli.paths.is_empty() || ctx.settings.target_dirs != strings(TARGET_DIRS);
+    let missing: Vec<&String> = ctx
+        .target_paths
+        .iter()
+        .filter(|p| !Path::new(p.as_str()).exists())
+        .collect();
+    if explicit || missing.len() == ctx.target_paths.len() {
+        for dir in &missing {
+            errors.push((
+                "target-dir-missing".to_string(),
+                format!("Target path `{dir}` does not exist"),
+            ));
+        }
+    }
+    out.push_str(&format!(
+        "target dirs: {} ({} missing)\n",
+        ctx.target_paths.join(", "),
+        missing.len()
+    ));
+
+    // CI scripts.
+    let (shell, powershell) = ci_scripts(ctx.settings.include_untracked);
+    let mut findings: Vec<ScriptFinding> = Vec::new();
+    if !shell.is_empty() {
+        match find_on_path("shellcheck") {
+            Some(path) => match run_shellcheck(&path, &shell, &hb) {
+                Ok(found) => findings.extend(found),
+                Err(err) => warnings.push(("shellcheck".to_string(), format!("{err:#}"))),
+            },
+            None => out.push_str(&format!(
+                "shellcheck not installed; {} shell script(s) not linted\n",
+                shell.len()
+            )),
+        }
+    }
+    if !powershell.is_empty() {
+        match find_on_path("pwsh").or_else(|| find_on_path("powershell")) {
+            Some(path) => match run_psscriptanalyzer(&path, &powershell, &hb) {
+                Ok((pssa, found)) => {
+                    if !pssa {
+                        out.push_str("PSScriptAnalyzer not installed; PowerShell parser only\n");
+                    }
+                    findings.extend(found);
+                }
+                Err(err) => warnings.push(("psscriptanalyzer".to_string(), format!("{err:#}"))),
+            },
+            None => out.push_str(&format!(
+                "PowerShell not installed; {} script(s) not checked\n",
+                powershell.len()
+            )),
+        }
+    }
+    parsed.details.insert(
+        "scripts".to_string(),
+        json!(shell.iter().chain(&powershell).collect::<Vec<_>>()),
+    );
+
+    for (rule, message) in errors.iter().chain(&warnings) {
+        out.push_str(&format!("{rule}: {message}\n"));
+        parsed.issues.push(Issue {
+            language: "ci".to_string(),
+            tool: "self-check".to_string(),
+            rule: rule.clone(),
+            count: 1,
+            message: Some(message.clone()),
+        });
+    }
+    let mut script_rules: BTreeMap<(&str, &str), Vec<&ScriptFinding>> = BTreeMap::new();
+    for f in &findings {
+        out.push_str(&format!(
+            "{}:{}: {} {}\n",
+            f.file, f.line, f.rule, f.message
+        ));
+        script_rules.entry((f.tool, &f.rule)).or_default().push(f);
+    }
+    for ((tool, rule), group) in &script_rules {
+        parsed.issues.push(Issue {
+            language: "ci".to_string(),
+            tool: (*tool).to_string(),
+            rule: (*rule).to_string(),
+            count: group.len(),
+            message: Some(group[0].message.clone()),
+        });
+    }
+
+    let script_errors = findings.iter().filter(|f| f.error).count();
+    let failures = errors.len() + script_errors;
+    parsed.warnings = warnings.len() + findings.len() - script_errors;
+    parsed.note = match (failures, parsed.warnings) {
+        (0, 0) => Some(format!(
+            "{} commands, {} scripts checked",
+            commands.len(),
+            shell.len() + powershell.len()
+        )),
+        (0, w) => Some(format!("{w} warnings")),
+        (f, _) => Some(format!(
+            "{f} problems: {}",
+            errors
+                .iter()
+                .map(|(_, m)| m.clone())
+                .chain(
+                    findings
+                        .iter()
+                        .filter(|f| f.error)
+                        .map(|f| format!("{}:{} {}", f.file, f.line, f.rule))
+                )
+                .take(3)
+                .collect::<Vec<_>>()
+                .join("; ")
+        )),
+    };
+
+    let mut res = ToolResult {
+        tool: tool_name.to_string(),
+        description: cfg.description.clone(),
+        command: String::new(),
+        available: true,
+        exit_code: i32::from(failures > 0),
+        stdout: out,
+        stderr: String::new(),
+        critical: cfg.critical,
+        can_fix: false,
+        fixed: false,
+        hung: false,
+        timed_out: false,
+        duration_ms: started.elapsed().as_millis(),
+        log_path: None,
+        fixed_files: Vec::new(),
+    };
+    write_tool_log(&mut res, ctx.cli.clamp);
+    (res, parsed)
+}
+
+/// Runs the fix command of a tool; returns its result and the files it changed.
+fn run_fix(
+    ctx: &RunContext,
+    tool_name: &str,
+    cfg: &ToolConfig,
+    hb: &HeartbeatConfig,
+) -> (ToolResult, Vec<String>) {
+    let before = worktree_snapshot(&ctx.target_paths);
+    let mut fix = run_tool(tool_name, cfg, &ctx.target_paths, true, ctx.cli.verbose, hb);
+    let changed = changed_since(&before, &worktree_snapshot(&ctx.target_paths));
+    fix.tool = format!("{tool_name}-fix");
+    write_tool_log(&mut fix, ctx.cli.clamp);
+    if ctx.cli.verbose {
+        eprintln!("{tool_name}: fix changed {} file(s)", changed.len());
+    }
+    (fix, changed)
+}
+
+/// Content hashes of files that may differ from a clean checkout.
+///
+/// * In git: modified and untracked (not ignored) files, so unchanged files cost nothing.
+/// * Outside git: every file under `target_paths`.
+/// * Submodules and `EXCLUDED_DIRS` are skipped either way (see `discover_files`).
+fn worktree_snapshot(target_paths: &[String]) -> BTreeMap<String, String> {
+    let git = Command::new("git")
+        .args([
+            "ls-files",
+            "-z",
+            "--modified",
+            "--others",
+            "--exclude-standard",
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success());
+    let submodules = submodule_paths();
+    let files: Vec<PathBuf> = match git {
+        Some(out) => String::from_utf8_lossy(&out.stdout)
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        None => discover_files(target_paths, true).unwrap_or_default(),
+    };
+    files
+        .into_iter()
+        .filter(|path| path.is_file() && !is_excluded(path, &submodules))
+        .filter_map(|path| {
+            let bytes = fs::read(&path).ok()?;
+            let hash: String = Sha256::digest(&bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            Some((path.to_string_lossy().replace('\\', "/"), hash))
+        })
+        .collect()
+}
+
+/// Files whose content differs between two snapshots (or that appear in only one).
+fn changed_since(
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut changed: BTreeSet<&String> = BTreeSet::new();
+    for (path, hash) in after {
+        if before.get(path) != Some(hash) {
+            changed.insert(path);
+        }
+    }
+    // * Dirty before, clean after: the fix restored the committed content.
+    changed.extend(before.keys().filter(|path| !after.contains_key(*path)));
+    changed.into_iter().cloned().collect()
+}
+
+/// Runs one tool (on a worker thread) and parses its output.
+fn execute_tool(ctx: &RunContext, tool_name: &str, cfg: &ToolConfig) -> (ToolResult, ParsedOutput) {
+    match cfg.builtin {
+        Some(Builtin::LineLimits) => run_line_limits(
+            tool_name,
+            cfg,
+            &ctx.target_paths,
+            &ctx.policy.line_limits,
+            ctx.settings.include_untracked,
+        ),
+        Some(Builtin::SelfCheck) => run_self_check(ctx, tool_name, cfg),
+        None => {
+            let mut hb = ctx.hb;
+            if hb.timeout_sec == 0 {
+                hb.timeout_sec = ctx
+                    .settings
+                    .stage(&cfg.stage)
+                    .timeout_sec
+                    .or(ctx.profile.timeout_sec)
+                    .unwrap_or(0);
+            }
+            let runs = if package_scoped(cfg) {
+                crate_runs(ctx, cfg)
+            } else {
+                Vec::new()
+            };
+            let scoped = packages_config(ctx, cfg);
+            // * Fixes run once over all selected crates (`cargo fmt -p ...` formats whole crates).
+            let fix = (ctx.cli.fix && cfg.can_fix && !cfg.args_fix.is_empty()).then(|| {
+                if runs.is_empty() {
+                    run_fix(ctx, tool_name, scoped.as_ref().unwrap_or(cfg), &hb)
+                } else {
+                    let crates: Vec<String> = runs.iter().map(|(name, _)| name.clone()).collect();
+                    run_fix(ctx, tool_name, &scoped_config(cfg, &crates), &hb)
+                }
+            });
+            // * After a fix, the check itself verifies what is left.
+            let (mut res, mut parsed) = if !runs.is_empty() {
+                run_per_crate(ctx, tool_name, cfg, &runs, &hb)
+            } else {
+                let mut res = run_tool(
+                    tool_name,
+                    scoped.as_ref().unwrap_or(cfg),
+                    &ctx.target_paths,
+                    false,
+                    ctx.cli.verbose,
+                    &hb,
+                );
+                let parsed = parse_output(cfg, &mut res, &ctx.policy);
+                (res, parsed)
+            };
+            write_tool_log(&mut res, ctx.cli.clamp);
+            if let Some((fix, fixed_files)) = fix {
+                // * Fixed means the worktree changed; the fix command's exit code stays in
+                //   `details.fix`.
+                res.fixed = !fixed_files.is_empty();
+                res.fixed_files = fixed_files;
+                parsed.details.insert(
+                    "fix".to_string(),
+                    json!({
+                        "command": fix.command,
+                        "exit_code": fix.exit_code,
+                        "log_path": fix.log_path,
+                    }),
+                );
+            }
+            (res, parsed)
+        }
+    }
+}
+
+fn run_all_checks(cli: &Cli) -> Result<RunOutcome> {
+    let started_at = Utc::now();
+    let started = Instant::now();
+
+    if cli.clean {
+        if cli.verbose {
+            eprintln!("Cleaning cache directory: {CACHE_DIR}");
+        }
+        clean_cache_dir()?;
+    }
+    let cache_dir = Path::new(CACHE_DIR);
+
+    // * A config that does not load is reported by `self-check`, which then blocks every
+    //   other stage; without `self-check` in the run the error aborts as before.
+    let self_check_selected = cli.tool.as_deref().is_none_or(|t| t == SELF_CHECK_STAGE)
+        && !cli.skip.iter().any(|s| s == SELF_CHECK_STAGE);
+    let (mut settings, config_broken) = match load_settings(cli.config.as_deref()) {
+        Ok(settings) => (settings, false),
+        Err(err) if self_check_selected => {
+            if cli.verbose {
+                eprintln!("{err:#}");
+            }
+            (default_settings(), true)
+        }
+        Err(err) => return Err(err),
+    };
+    if cli.nextest {
+        if let Some(test) = settings.tools.get_mut("cargo-test") {
+            test.description = "Test runner (cargo nextest)".to_string();
+            test.fallback_args = std::mem::replace(&mut test.args, strings(NEXTEST_ARGS));
+        }
+    }
+    let mut configs = settings.tools.clone();
+    if cli.orchestrate() {
+        configs.extend(settings.layer_tools());
+    }
+
+    if let Some(ref only) = cli.tool {
+        if !configs.contains_key(only.as_str()) {
+            return Err(anyhow!("Unknown tool: {}", only));
+        }
+    }
+
+    let mut tools_to_run: Vec<String> = configs.keys().cloned().collect();
+
+    // Stage order; tools of one stage keep name order.
+    let order = stage_order(&configs)?;
+    tools_to_run.sort_by_key(|name| order.iter().position(|s| *s == configs[name].stage));
+
+    let mut target_paths = if cli.paths.is_empty() {
+        settings.target_dirs.clone()
+    } else {
+        cli.paths.clone()
+    };
+
+    let profile = match settings.profile(&cli.profile) {
+        Err(_) if config_broken => settings.profile(DEFAULT_PROFILE)?,
+        profile => profile?,
+    };
+    let known_stages = settings.known_stages();
+    if let Some(unknown) = cli.skip.iter().find(|s| !known_stages.contains(*s)) {
+        return Err(anyhow!("Unknown stage for --skip: {}", unknown));
+    }
+
+    // * `--path` narrows cargo tools to the members it touches; all members means no `-p`.
+    let mut packages: Vec<String> = Vec::new();
+    let mut scope: Option<RunScope> = None;
+    let mut crate_files: Option<Vec<(WorkspaceMember, Vec<String>)>> = None;
+    if cli.changed_since.is_some() || cli.staged {
+        let mut files = git_changed_files(cli.changed_since.as_deref())?;
+        let within = |file: &str, roots: &[String]| {
+            let file = absolute_path(file);
+            roots
+                .iter()
+                .any(|root| file.starts_with(absolute_path(root)))
+        };
+        if !cli.paths.is_empty() {
+            files.retain(|file| within(file, &cli.paths));
+        }
+
+        let mut crates: BTreeSet<String> = BTreeSet::new();
+        crate_files = Some(Vec::new());
+        if let Some(workspace) = Workspace::load() {
+            let wide = files
+                .iter()
+                .any(|file| workspace.is_workspace_wide(&absolute_path(file)));
+            crates = if wide {
+                workspace.members.iter().map(|m| m.name.clone()).collect()
+            } else {
+                workspace.with_dependents(workspace.members_for_paths(&files))
+            };
+            if crates.len() < workspace.members.len() {
+                packages = crates.iter().cloned().collect();
+            }
+            let mut by_member: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for file in &files {
+                if !file.ends_with(".rs") || !Path::new(file).is_file() {
+                    continue;
+                }
+                if let Some(member) = workspace.member_of(&absolute_path(file)) {
+                    by_member
+                        .entry(member.name.clone())
+                        .or_default()
+                        .push(file.clone());
+                }
+            }
+            // * After a workspace-wide change (e.g. `rustfmt.toml`), fmt checks every crate.
+            crate_files = (!wide).then(|| {
+                workspace
+                    .members
+                    .iter()
+                    .filter_map(|m| Some((m.clone(), by_member.remove(&m.name)?)))
+                    .collect()
+            });
+        }
+
+        // * Line limits see only the changed files that are inside the target dirs.
+        target_paths = files
+            .iter()
+            .filter(|file| Path::new(file).is_file() && within(file, &target_paths))
+            .cloned()
+            .collect();
+        let run_scope = RunScope {
+            mode: if cli.staged {
+                "staged"
+            } else {
+                "changed-since"
+            }
+            .to_string(),
+            base: cli.changed_since.clone(),
+            files,
+            crates: crates.into_iter().collect(),
+        };
+        if cli.verbose {
+            eprintln!("Scope: {}", run_scope.describe());
+        }
+        scope = Some(run_scope);
+    } else if !cli.paths.is_empty() {
+        if let Some(workspace) = Workspace::load() {
+            let selected = workspace.members_for_paths(&cli.paths);
+            if !selected.is_empty() && selected.len() < workspace.members.len() {
+                packages = selected.into_iter().collect();
+            }
+            if cli.verbose {
+                eprintln!(
+                    "Workspace scope: {}",
+                    if packages.is_empty() {
+                        "all members".to_string()
+                    } else {
+                        packages.join(", ")
+                    }
+                );
+            }
+        }
+    }
+
+    let mut ctx = RunContext {
+        cli,
+        settings: &settings,
+        profile,
+        policy: settings.policy(cli, profile),
+        hb: cli.heartbeat(),
+        target_paths,
+        tool_versions: BTreeMap::new(),
+        packages,
+        scope,
+        crate_files,
+    };
+
+    // * Slots keep stage order in the report regardless of completion order.
+    let mut slots: Vec<Option<ToolSlot>> = tools_to_run.iter().map(|_| None).collect();
+    let mut pending: Vec<usize> = Vec::new();
+
+    for (idx, tool_name) in tools_to_run.iter().enumerate() {
+        let cfg = &configs[tool_name];
+        // * An explicit `--tool` overrides the profile, but not `--skip`.
+        let skip_note = if cli.tool.as_deref().is_some_and(|only| only != tool_name) {
+            Some("Not selected (--tool)".to_string())
+        } else if let Some(note) = ctx.scope.as_ref().and_then(|scope| {
+            // * Coverage (and CovRank) would still measure the whole workspace.
+            if cfg.parser == OutputParser::Coverage {
+                Some(format!(
+                    "Skipped: coverage is not measured in partial runs ({})",
+                    scope.describe()
+                ))
+            } else if !package_scoped(cfg) {
+                None
+            } else if cfg.args.first().is_some_and(|sub| sub == "fmt") {
+                ctx.crate_files
+                    .as_ref()
+                    .is_some_and(Vec::is_empty)
+                    .then(|| format!("Skipped: no Rust file changed ({})", scope.describe()))
+            } else {
+                scope
+                    .crates
+                    .is_empty()
+                    .then(|| format!("Skipped: no crate affected ({})", scope.describe()))
+            }
+        }) {
+            Some(note)
+        } else if cli.skip.contains(&cfg.stage) {
+            Some("Skipped (--skip)".to_string())
+        } else if cli.tool.is_none() && !profile.includes(&cfg.stage) {
+            Some(format!("Not in profile `{}`", cli.profile))
+        } else {
+            None
+        };
+        match skip_note {
+            Some(note) => slots[idx] = Some((skip_stage(cfg, tool_name, note), None)),
+            None => pending.push(idx),
+        }
+    }
+
+    // * First critical failure (fail-fast), and every stage that failed or was skipped
+    //   because of a failed dependency (`--keep-going`).
+    let mut failed_stage: Option<String> = None;
+    let mut blocked_stages: BTreeSet<String> = BTreeSet::new();
+    // * Unavailable or too-old tools are decided before anything runs.
+    let preflight = run_preflight(&ctx, &configs, &tools_to_run, &pending);
+    pending.retain(|idx| {
+        let Some((status, note)) = preflight.blocked.get(idx) else {
+            return true;
+        };
+        let cfg = &configs[&tools_to_run[*idx]];
+        let mut stage = skip_stage(cfg, &tools_to_run[*idx], note.clone());
+        stage.status = *status;
+        if *status == StageStatus::Fail {
+            blocked_stages.insert(cfg.stage.clone());
+            if failed_stage.is_none() {
+                failed_stage = Some(cfg.stage.clone());
+            }
+        }
+        slots[*idx] = Some((stage, None));
+        false
+    });
+    ctx.tool_versions = preflight.versions;
+    // * Hash inputs once, before any tool runs, so a tool that touches the tree (or a
+    //   rescheduling pass) cannot change the key it is cached under.
+    let hashes: Vec<Option<String>> = tools_to_run
+        .iter()
+        .enumerate()
+        .map(|(idx, tool_name)| match pending.contains(&idx) {
+            true => tool_inputs_hash(&ctx, &configs[tool_name]),
+            false => Ok(None),
+        })
+        .collect::<Result<_>>()?;
+    let jobs = cli.jobs();
+    // * Every stage implicitly depends on `self-check`, even with `--keep-going`.
+    let depends_on = |cfg: &ToolConfig, stage: &str| {
+        cfg.depends_on.iter().any(|dep| dep == stage)
+            || (stage == SELF_CHECK_STAGE && cfg.stage != SELF_CHECK_STAGE)
+    };
+
+    thread::scope(|scope| -> Result<()> {
+        let (tx, rx) = mpsc::channel::<(usize, ToolResult, ParsedOutput)>();
+        // Running tool index -> lock it holds.
+        let mut running: BTreeMap<usize, Option<&str>> = BTreeMap::new();
+
+        loop {
+            // * Decide pending tools in stage order while a job slot is free; a tool waits
+            //   for its dependency stages and its lock (earlier holders go first).
+            let mut waiting_locks: BTreeSet<&str> = BTreeSet::new();
+            let mut k = 0;
+            while k < pending.len() && running.len() < jobs {
+                let idx = pending[k];
+                let tool_name = &tools_to_run[idx];
+                let cfg = &configs[tool_name];
+                let lock = cfg.lock.as_deref();
+
+                let deps_unfinished = pending[..k]
+                    .iter()
+                    .chain(running.keys())
+                    .any(|j| depends_on(cfg, &configs[&tools_to_run[*j]].stage));
+                if deps_unfinished {
+                    waiting_locks.extend(lock);
+                    k += 1;
+                    continue;
+                }
+
+                // * Fail-fast lets the rest of the failed stage finish.
+                let blocked_dep = blocked_stages
+                    .iter()
+                    .find(|stage| depends_on(cfg, stage))
+                    .cloned();
+                let skip_note = if let Some(dep) = blocked_dep {
+                    blocked_stages.insert(cfg.stage.clone());
+                    Some(format!("Skipped: dependency `{dep}` did not pass"))
+                } else {
+                    failed_stage
+                        .as_ref()
+                        .filter(|failed| **failed != cfg.stage && !cli.keep_going)
+                        .map(|failed| format!("Skipped: `{failed}` failed (fail-fast)"))
+                };
+                if let Some(note) = skip_note {
+                    slots[idx] = Some((skip_stage(cfg, tool_name, note), None));
+                    pending.remove(k);
+                    continue;
+                }
+
+                if let Some(hash) = &hashes[idx] {
+                    if cli.use_cache() && is_cache_hit(cache_dir, tool_name, hash) {
+                        let stage = StageResult {
+                            name: cfg.stage.clone(),
+                            status: StageStatus::Cached,
+                            note: Some("Cache hit".to_string()),
+                            details: Some(json!({ "tool": tool_name, "hash": hash })),
+                            duration_ms: 0,
+                        };
+                        slots[idx] = Some((stage, None));
+                        pending.remove(k);
+                        continue;
+                    }
+                }
+
+                let lock_busy = lock.is_some_and(|l| {
+                    waiting_locks.contains(l) || running.values().any(|held| *held == Some(l))
+                });
+                if lock_busy {
+                    waiting_locks.extend(lock);
+                    k += 1;
+                    continue;
+                }
+
+                running.insert(idx, lock);
+                pending.remove(k);
+                let tx = tx.clone();
+                let ctx = &ctx;
+                scope.spawn(move || {
+                    let (res, parsed) = execute_tool(ctx, tool_name, cfg);
+                    let _ = tx.send((idx, res, parsed));
+                });
+            }
+
+            if running.is_empty() {
+                break;
+            }
+
+            let (idx, res, parsed) = rx.recv().context("Tool worker exited unexpectedly")?;
+            running.remove(&idx);
+            let tool_name = &tools_to_run[idx];
+            let cfg = &configs[tool_name];
+            if cfg.builtin.is_some() {
+                eprint!("{}", res.stdout);
+            }
+            let stage = stage_from_result(cfg, &res, &parsed);
+
+            // * Write stamps only on success; drop a stale trust stamp otherwise.
+            match (&hashes[idx], stage.status) {
+                (Some(hash), StageStatus::Ok) => write_cache_stamp(cache_dir, tool_name, hash)?,
+                _ => clear_trust_stamp(cache_dir, tool_name),
+            }
+
+            if stage.status == StageStatus::Fail {
+                blocked_stages.insert(stage.name.clone());
+                if cfg.critical && failed_stage.is_none() {
+                    failed_stage = Some(stage.name.clone());
+                }
+            }
+
+            slots[idx] = Some((stage, Some((res, parsed))));
+        }
+        Ok(())
+    })?;
+
+    prune_logs(Path::new(LOG_DIR), settings.logs_max);
+
+    let mut results: Vec<ToolResult> = Vec::new();
+    let mut stages: Vec<StageResult> = Vec::new();
+    let mut issues: Vec<Issue> = Vec::new();
+    let mut metrics = Metrics::default();
+
+    for (stage, run) in slots.into_iter().flatten() {
+        if let Some((res, parsed)) = run {
+            let disabled = ctx.policy.disabled_rules.get(&stage.name);
+            issues.extend(
+                parsed
+                    .issues
+                    .into_iter()
+                    .filter(|i| disabled.is_none_or(|rules| !rules.contains(&i.rule))),
+            );
+            if let Some(counts) = parsed.test_counts {
+                metrics.test_counts = Some(counts);
+            }
+            if let Some(coverage) = parsed.coverage {
+                metrics.coverage = Some(coverage);
+            }
+            results.push(res);
+        }
+        stages.push(stage);
+    }
+
+    Ok(RunOutcome {
+        started_at,
+        finished_at: Utc::now(),
+        duration_ms: started.elapsed().as_millis(),
+        results,
+        stages,
+        issues,
+        metrics,
+        history_max: settings.history_max,
+        tool_versions: ctx.tool_versions,
+        scope: ctx.scope,
+    })
+}
+
+fn print_summary(
+    stages: &[StageResult],
+    status: StageStatus,
+    duration_ms: u128,
+    scope: Option<&RunScope>,
+) {
+    eprintln!("Status: {}", status.as_str());
+    if let Some(scope) = scope {
+        eprintln!("Scope: PARTIAL ({})", scope.describe());
+    }
+    eprintln!("Duration: {duration_ms}ms");
+    for stage in stages {
+        let note = stage
+            .note
+            .as_deref()
+            .map(|n| format!(" ({n})"))
+            .unwrap_or_default();
+        println!(
+            "  {:<15} {}{}",
+            stage.name,
+            stage.status.as_str().to_uppercase(),
+            note
+        );
+        // * Point to the full log only where someone needs to look.
+        let log_path = stage
+            .details
+            .as_ref()
+            .and_then(|d| d.get("log_path"))
+            .and_then(Value::as_str);
+        if let (StageStatus::Fail | StageStatus::Warn, Some(log_path)) = (stage.status, log_path) {
+            println!("  {:<15} log: {log_path}", "");
+        }
+        let fixed = stage
+            .details
+            .as_ref()
+            .and_then(|d| d.get("fixed_files"))
+            .and_then(Value::as_array);
+        for file in fixed.into_iter().flatten().filter_map(Value::as_str) {
+            println!("  {:<15} fixed: {file}", "");
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if let Some(CliCommand::Stats(ref args)) = cli.command {
+        return run_stats(args, cli.json);
+    }
+    if let Some(CliCommand::Diff(ref args)) = cli.command {
+        return run_diff(args, cli.json);
+    }
+    if let Some(CliCommand::History(ref command)) = cli.command {
+        return run_history(command, cli.json);
+    }
+
+    let outcome = run_all_checks(&cli).context("Failed to run Rust checks")?;
+    let status = outcome.status();
+    let history_max = outcome.history_max;
+
+    if cli.covrank > 0 {
+        print_covrank(&outcome.stages, cli.covrank);
+    }
+
+    let (report, legacy) = outcome.into_reports();
+
+    // * A broken log must not hide the CI result.
+    if cli.orchestrate() {
+        if let Err(err) = write_report(&report, history_max) {
+            eprintln!("Failed to write {REPORT_PATH}: {err:#}");
+        }
+    }
+    if let Err(err) = write_enforcer_logs(&report) {
+        eprintln!("Failed to write Enforcer logs: {err:#}");
+    }
+
+    if cli.legacy_json {
+        println!("{}", serde_json::to_string_pretty(&legacy)?);
+    } else if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_summary(
+            &report.stages,
+            status,
+            report.duration_ms,
+            report.scope.as_ref(),
+        );
+        if cli.orchestrate() {
+            eprintln!("Report: {REPORT_PATH}");
+        }
+    }
+
+    if status == StageStatus::Fail {
+        Err(anyhow!(if cli.orchestrate() {
+            "CI failed"
+        } else {
+            "Rust checks failed"
+        }))
+    } else {
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(tool: &str, exit_code: i32) -> ToolResult {
+        ToolResult {
+            tool: tool.to_string(),
+            available: true,
+            exit_code,
+            critical: true,
+            ..ToolResult::default()
+        }
+    }
+
+    fn stage(status: StageStatus) -> StageResult {
+        StageResult {
+            name: "x".to_string(),
+            status,
+            note: None,
+            details: None,
+            duration_ms: 0,
+        }
+    }
+
+    fn outcome(results: Vec<ToolResult>) -> RunOutcome {
+        let configs = tools_config();
+        let stages = results
+            .iter()
+            .map(|r| stage_from_result(&configs[r.tool.as_str()], r, &ParsedOutput::default()))
+            .collect();
+        let now = Utc::now();
+        RunOutcome {
+            started_at: now,
+            finished_at: now,
+            duration_ms: 5,
+            results,
+            stages,
+            issues: Vec::new(),
+            metrics: Metrics::default(),
+            history_max: HISTORY_MAX_DEFAULT,
+            tool_versions: BTreeMap::new(),
+            scope: None,
+        }
+    }
+
+    #[test]
+    fn overall_status_prefers_fail_then_warn() {
+        use StageStatus::*;
+        assert_eq!(overall_status(&[]), Ok);
+        assert_eq!(overall_status(&[stage(Ok), stage(Skip)]), Ok);
+        assert_eq!(overall_status(&[stage(Ok), stage(Warn)]), Warn);
+        assert_eq!(overall_status(&[stage(Warn), stage(Fail)]), Fail);
+    }
+
+    #[test]
+    fn stage_from_result_maps_exit_code_and_criticality() {
+        let mut cfg = tools_config()["cargo-test"].clone();
+        let ok = stage_from_result(&cfg, &result("cargo-test", 0), &ParsedOutput::default());
+        assert_eq!(ok.name, "test");
+        assert_eq!(ok.status, StageStatus::Ok);
+        assert_eq!(ok.note, None);
+
+        let failed = stage_from_result(&cfg, &result("cargo-test", 101), &ParsedOutput::default());
+        assert_eq!(failed.status, StageStatus::Fail);
+        assert_eq!(failed.note.as_deref(), Some("Exit code: 101"));
+
+        cfg.critical = false;
+        let warned = stage_from_result(&cfg, &result("cargo-test", 101), &ParsedOutput::default());
+        assert_eq!(warned.status, StageStatus::Warn);
+
+        let missing = ToolResult {
+            available: false,
+            ..result("cargo-test", 127)
+        };
+        assert_eq!(
+            stage_from_result(&cfg, &missing, &ParsedOutput::default()).status,
+            StageStatus::Warn
+        );
+        cfg.critical = true;
+        assert_eq!(
+            stage_from_result(&cfg, &missing, &ParsedOutput::default()).status,
+            StageStatus::Fail
+        );
+    }
+
+    #[test]
+    fn report_carries_schema_fields() {
+        let (report, _) =
+            outcome(vec![result("cargo-fmt", 0), result("cargo-test", 1)]).into_reports();
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["status"], "fail");
+        assert_eq!(value["stages"][0]["name"], "fmt");
+        assert_eq!(value["stages"][1]["status"], "fail");
+        assert!(value["started_at_utc"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn watchdog_failures_follow_criticality() {
+        let mut cfg = tools_config()["cargo-test"].clone();
+        let hung = ToolResult {
+            hung: true,
+            ..result("cargo-test", -1)
+        };
+        let timed_out = ToolResult {
+            timed_out: true,
+            ..result("cargo-test", -1)
+        };
+        assert_eq!(
+            stage_from_result(&cfg, &hung, &ParsedOutput::default()).status,
+            StageStatus::Fail
+        );
+        cfg.critical = false;
+        let stage = stage_from_result(&cfg, &hung, &ParsedOutput::default());
+        assert_eq!(stage.status, StageStatus::Warn);
+        assert_eq!(stage.note.as_deref(), Some("Process hung"));
+        let stage = stage_from_result(&cfg, &timed_out, &ParsedOutput::default());
+        assert_eq!(stage.status, StageStatus::Warn);
+        assert_eq!(stage.note.as_deref(), Some("Timed out"));
+    }
+
+    #[test]
+    fn heartbeat_formatting() {
+        assert_eq!(format_elapsed(Duration::from_secs(3725)), "01:02:05");
+        assert_eq!(snippet("  short  "), "short");
+        let long = "x".repeat(80);
+        assert_eq!(snippet(&long), format!("{}...", "x".repeat(57)));
+    }
+
+    #[cfg(unix)]
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[cfg(unix)]
+    const QUIET: HeartbeatConfig = HeartbeatConfig {
+        heartbeat_sec: 0,
+        timeout_sec: 0,
+        same_line_hang_pulses: 0,
+        same_line_hang_min_sec: 0,
+    };
+
+    #[cfg(unix)]
+    #[test]
+    fn heartbeat_captures_output_and_exit_code() {
+        let captured =
+            run_with_heartbeat("t", &mut sh("echo out; echo err >&2; exit 3"), &QUIET).unwrap();
+        assert_eq!(captured.exit_code, 3);
+        assert_eq!(captured.stdout, "out\n");
+        assert_eq!(captured.stderr, "err\n");
+        assert!(!captured.hung && !captured.timed_out);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn heartbeat_kills_on_timeout() {
+        let hb = HeartbeatConfig {
+            timeout_sec: 1,
+            ..QUIET
+        };
+        let started = Instant::now();
+        let captured = run_with_heartbeat("t", &mut sh("echo start; sleep 30"), &hb).unwrap();
+        assert!(captured.timed_out);
+        assert_eq!(captured.exit_code, -1);
+        assert_eq!(captured.stdout, "start\n");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn heartbeat_detects_same_line_hang() {
+        let hb = HeartbeatConfig {
+            heartbeat_sec: 1,
+            same_line_hang_pulses: 1,
+            ..QUIET
+        };
+        let captured = run_with_heartbeat("t", &mut sh("echo stuck; sleep 30"), &hb).unwrap();
+        assert!(captured.hung);
+        assert!(!captured.timed_out);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn heartbeat_returns_when_grandchild_holds_pipes() {
+        let started = Instant::now();
+        let captured =
+            run_with_heartbeat("t", &mut sh("sleep 20 & echo done; exit 0"), &QUIET).unwrap();
+        assert_eq!(captured.exit_code, 0);
+        assert_eq!(captured.stdout, "done\n");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    /// Fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("build-rs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn inputs_hash_covers_all_sources_manifests_and_args() {
+        let root = scratch_dir("hash");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("crates/core/src")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("crates/core/src/lib.rs"), "").unwrap();
+        fs::write(root.join("Cargo.toml"), "[workspace]").unwrap();
+        let cfg = tools_config()["cargo-test"].clone();
+        let dirs = strings(TARGET_DIRS);
+        let hash = |cfg: &ToolConfig| in_dir(&root, || compute_inputs_hash(cfg, &dirs, true));
+        let base = hash(&cfg).unwrap();
+        assert_eq!(hash(&cfg).unwrap(), base);
+
+        // * Build output is not an input.
+        fs::write(root.join("target/out.bin"), "x").unwrap();
+        assert_eq!(hash(&cfg).unwrap(), base);
+
+        // * An edit in another crate invalidates the cache even when `--path src` was given.
+        fs::write(root.join("crates/core/src/lib.rs"), "pub fn f() {}").unwrap();
+        let edited = hash(&cfg).unwrap();
+        assert_ne!(edited, base);
+
+        fs::write(root.join("Cargo.lock"), "# lock").unwrap();
+        let locked = hash(&cfg).unwrap();
+        assert_ne!(locked, edited);
+
+        let mut other_args = cfg.clone();
+        other_args.args.push("--release".to_string());
+        assert_ne!(hash(&other_args).unwrap(), locked);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn clamp_output_keeps_head_and_tail_and_points_to_the_log() {
+        assert_eq!(clamp_output("short", 10, None), "short");
+        assert_eq!(clamp_output("0123456789", 0, None), "0123456789");
+
+        let clamped = clamp_output("0123456789", 4, Some(".ci_cache/logs/t.log"));
+        assert_eq!(
+            clamped,
+            "01\n... [6 chars clamped; full output: .ci_cache/logs/t.log] ...\n89"
+        );
+    }
+
+    #[test]
+    fn prune_logs_keeps_the_newest() {
+        let dir = scratch_dir("prune-logs");
+        for name in ["a.log", "b.log", "c.log", "notes.txt"] {
+            fs::write(dir.join(name), name).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        prune_logs(&dir, 0);
+        assert!(dir.join("a.log").exists());
+
+        prune_logs(&dir, 2);
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["b.log", "c.log", "notes.txt"]);
+
+        let settings = load_settings(Some(&write_config(
+            "logs-max",
+            r#"{ "logs": { "max_count": 7 } }"#,
+        )))
+        .unwrap();
+        assert_eq!(settings.logs_max, 7);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cache_hit_needs_matching_hash_and_trust_stamp() {
+        let cache = scratch_dir("stamps");
+        assert!(!is_cache_hit(&cache, "cargo-test", "abc"));
+        write_cache_stamp(&cache, "cargo-test", "abc").unwrap();
+        assert!(is_cache_hit(&cache, "cargo-test", "abc"));
+        assert!(!is_cache_hit(&cache, "cargo-test", "def"));
+        assert!(!is_cache_hit(&cache, "cargo-fmt", "abc"));
+        clear_trust_stamp(&cache, "cargo-test");
+        assert!(!is_cache_hit(&cache, "cargo-test", "abc"));
+        let _ = fs::remove_dir_all(&cache);
+    }
+
+    #[test]
+    fn clean_exit_with_warnings_maps_to_warn() {
+        let cfg = tools_config()["cargo-clippy"].clone();
+        let parsed = ParsedOutput {
+            warnings: 2,
+            ..ParsedOutput::default()
+        };
+        let stage = stage_from_result(&cfg, &result("cargo-clippy", 0), &parsed);
+        assert_eq!(stage.status, StageStatus::Warn);
+        assert_eq!(stage.note.as_deref(), Some("2 warnings (non-critical)"));
+    }
+
+    fn compiler_message(level: &str, code: Option<&str>, message: &str, line: u64) -> String {
+        json!({
+            "reason": "compiler-message",
+            "package_id": "demo 0.1.0 (path+file:///tmp/demo)",
+            "message": {
+                "level": level,
+                "code": code.map(|c| json!({ "code": c, "explanation": null })),
+                "message": message,
+                "spans": [
+                    { "file_name": "src/lib.rs", "line_start": line, "column_start": 5,
+                      "is_primary": false },
+                    { "file_name": "src/lib.rs", "line_start": line, "column_start": 9,
+                      "is_primary": true }
+                ],
+                "rendered": format!("{level}: {message}\n --> src/lib.rs:{line}:9\n\n")
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn cargo_diagnostics() {
+        let warning = compiler_message(
+            "warning",
+            Some("clippy::needless_return"),
+            "unneeded `return` statement",
+            3,
+        );
+        let error = compiler_message("error", None, "expected one of `,` or `}`", 8);
+        let aborting = json!({
+            "reason": "compiler-message",
+            "message": { "level": "error", "code": null, "spans": [],
+                         "message": "aborting due to 1 previous error" }
+        });
+        let stdout = [
+            r#"{"reason":"compiler-artifact","package_id":"dep 1.0.0"}"#,
+            &warning,
+            // * `--all-targets`: the same warning again for the test target.
+            &warning,
+            &error,
+            &aborting.to_string(),
+            r#"{"reason":"build-finished","success":false}"#,
+            "plain text from a build script",
+        ]
+        .join("\n");
+        let mut res = ToolResult {
+            stdout,
+            ..result("cargo-clippy", 101)
+        };
+        let parsed = parse_cargo_diagnostics(&mut res);
+
+        assert_eq!(parsed.warnings, 1);
+        assert_eq!(parsed.note.as_deref(), Some("2 diagnostics (2 rules)"));
+        assert_eq!(parsed.details["diagnostics_total"], json!(2));
+        let issues: Vec<(&str, &str, usize)> = parsed
+            .issues
+            .iter()
+            .map(|i| (i.tool.as_str(), i.rule.as_str(), i.count))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                ("clippy", "clippy::needless_return", 1),
+                ("clippy", "rustc::error", 1)
+            ]
+        );
+        assert_eq!(
+            parsed.details["diagnostics"][0],
+            json!({
+                "rule": "clippy::needless_return",
+                "level": "warning",
+                "file": "src/lib.rs",
+                "line": 3,
+                "column": 9,
+                "message": "unneeded `return` statement"
+            })
+        );
+        assert_eq!(
+            res.stdout,
+            "warning: unneeded `return` statement\n --> src/lib.rs:3:9\n\n\
+             error: expected one of `,` or `}`\n --> src/lib.rs:8:9\n\n\
+             plain text from a build script\n"
+        );
+    }
+
+    #[test]
+    fn cargo_diagnostics_clean_build() {
+        let mut res = ToolResult {
+            stdout: r#"{"reason":"build-finished","success":true}"#.to_string(),
+            ..result("cargo-clippy", 0)
+        };
+        let parsed = parse_cargo_diagnostics(&mut res);
+        assert!(parsed.issues.is_empty());
+        assert_eq!(parsed.warnings, 0);
+        assert!(parsed.note.is_none());
+    }
+
+    fn output(stdout: &str, stderr: &str, exit_code: i32) -> ToolResult {
+        ToolResult {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            ..result("cargo-test", exit_code)
+        }
+    }
+
+    fn test_summary(parsed: &ParsedOutput) -> (u64, u64, u64, u64, Vec<Value>) {
+        let counts = parsed.test_counts.expect("test counts");
+        let failed = parsed.details["failed_tests"].as_array().unwrap().clone();
+        (
+            counts.total,
+            counts.passed,
+            counts.failed,
+            counts.skipped,
+            failed,
+        )
+    }
+
+    #[test]
+    fn libtest_with_doctests() {
+        let stdout = "
+running 3 tests
+test tests::adds ... ok
+test tests::slow ... ignored
+test tests::divides ... FAILED
+
+failures:
+
+---- tests::divides stdout ----
+
+thread 'tests::divides' panicked at src/lib.rs:20:9:
+assertion `left == right` failed
+  left: 2
+ right: 3
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    tests::divides
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+running 2 tests
+test src/lib.rs - add (line 3) ... ok
+test src/lib.rs - div (line 12) ... FAILED
+
+failures:
+
+---- src/lib.rs - div (line 12) stdout ----
+Test executable failed (exit status: 101).
+
+stderr:
+
+thread 'main' panicked at src/lib.rs:5:1:
+attempt to divide by zero
+
+
+failures:
+    src/lib.rs - div (line 12)
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.21s
+";
+        let mut res = output(stdout, "error: 2 targets failed", 101);
+        let parsed = parse_test_output(&mut res);
+
+        let (total, passed, failed, skipped, failed_tests) = test_summary(&parsed);
+        assert_eq!((total, passed, failed, skipped), (5, 2, 2, 1));
+        assert_eq!(
+            failed_tests,
+            [
+                json!({
+                    "name": "tests::divides",
+                    "message": "assertion `left == right` failed\n  left: 2\n right: 3"
+                }),
+                json!({
+                    "name": "src/lib.rs - div (line 12)",
+                    "message": "attempt to divide by zero"
+                }),
+            ]
+        );
+        assert_eq!(
+            parsed.note.as_deref(),
+            Some("2 tests failed: tests::divides, src/lib.rs - div (line 12)")
+        );
+    }
+
+    #[test]
+    fn libtest_old_panic_format_and_passing_run() {
+        let stdout = "
+running 1 test
+test it_fails ... FAILED
+
+failures:
+
+---- it_fails stdout ----
+thread 'it_fails' panicked at 'boom', tests/it.rs:4:5
+
+failures:
+    it_fails
+
+test result: FAILED. 0 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out
+";
+        let parsed = parse_test_output(&mut output(stdout, "", 101));
+        let (_, _, _, _, failed_tests) = test_summary(&parsed);
+        assert_eq!(
+            failed_tests[0]["message"],
+            json!("thread 'it_fails' panicked at 'boom', tests/it.rs:4:5")
+        );
+
+        let ok = "test result: ok. 4 passed; 0 failed; 2 ignored; 0 measured; 0 filtered out";
+        let parsed = parse_test_output(&mut output(ok, "", 0));
+        assert_eq!(parsed.note.as_deref(), Some("4 passed, 2 skipped"));
+    }
+
+    #[test]
+    fn nextest_summary() {
+        let stderr = "
+    Starting 4 tests across 2 binaries (1 test skipped)
+        PASS [   0.003s] demo tests::adds
+        FAIL [   0.004s] demo tests::divides
+        FAIL [   0.005s] demo::it parses
+        PASS [   0.002s] demo::it formats
+------------
+     Summary [   0.006s] 4 tests run: 2 passed, 2 failed, 1 skipped
+        FAIL [   0.004s] demo tests::divides
+        FAIL [   0.005s] demo::it parses
+error: test run failed
+";
+        let mut res = output("", stderr, 100);
+        let parsed = parse_test_output(&mut res);
+
+        let (total, passed, failed, skipped, failed_tests) = test_summary(&parsed);
+        assert_eq!((total, passed, failed, skipped), (5, 2, 2, 1));
+        let names: Vec<&str> = failed_tests
+            .iter()
+            .filter_map(|t| t["name"].as_str())
+            .collect();
+        assert_eq!(names, ["demo tests::divides", "demo::it parses"]);
+    }
+
+    #[test]
+    fn libtest_json_events() {
+        let stdout = r#"{ "type": "suite", "event": "started", "test_count": 2 }
+{ "type": "test", "event": "started", "name": "tests::a" }
+{ "type": "test", "name": "tests::a", "event": "ok" }
+{ "type": "test", "name": "tests::b", "event": "failed", "stdout": "\nthread 'tests::b' panicked at src/lib.rs:9:5:\nboom\nnote: run with `RUST_BACKTRACE=1`\n" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": 0.001 }
+"#;
+        let parsed = parse_test_output(&mut output(stdout, "", 101));
+        let (total, passed, failed, _, failed_tests) = test_summary(&parsed);
+        assert_eq!((total, passed, failed), (2, 1, 1));
+        assert_eq!(
+            failed_tests,
+            [json!({ "name": "tests::b", "message": "boom" })]
+        );
+    }
+
+    #[test]
+    fn test_output_without_summary() {
+        let stderr = "error[E0425]: cannot find value `x` in this scope";
+        let parsed = parse_test_output(&mut output("", stderr, 101));
+        assert!(parsed.test_counts.is_none());
+        assert!(parsed.note.is_none());
+    }
+
+    fn policy() -> StagePolicy {
+        StagePolicy {
+            coverage: CoverageThresholds {
+                warn: 75.0,
+                fail: 60.0,
+            },
+            covrank_limit: COVRANK_DETAILS_DEFAULT,
+            line_limits: LineLimits::default(),
+            disabled_rules: BTreeMap::new(),
+            security: security_policy(Severity::High, &[]),
+        }
+    }
+
+    fn llvm_cov_export(lines: f64, branches_count: u64) -> String {
+        json!({
+            "type": "llvm.coverage.json.export",
+            "data": [{
+                "totals": {
+                    "lines": { "count": 200, "covered": 160, "percent": lines },
+                    "functions": { "count": 20, "covered": 17, "percent": 85.0 },
+                    "branches": { "count": branches_count, "covered": 0, "percent": 0.0 }
+                }
+            }]
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn coverage_from_llvm_cov_export() {
+        let mut res = output(&llvm_cov_export(80.123, 0), "", 0);
+        let parsed = parse_coverage(&mut res, &policy());
+        let coverage = parsed.coverage.unwrap();
+        assert_eq!(coverage.lines_percent, 80.12);
+        assert_eq!(coverage.functions_percent, Some(85.0));
+        // * No instrumented branches: reported as absent, not 0%.
+        assert_eq!(coverage.branches_percent, None);
+        assert_eq!(parsed.status, Some(StageStatus::Ok));
+        assert_eq!(parsed.note.as_deref(), Some("80.12% lines"));
+        assert!(parsed.issues.is_empty());
+        assert_eq!(res.stdout, "lines=80.12% functions=85% branches=n/a\n");
+    }
+
+    #[test]
+    fn coverage_thresholds_map_to_warn_and_fail() {
+        let parsed = parse_coverage(&mut output(&llvm_cov_export(70.0, 4), "", 0), &policy());
+        assert_eq!(parsed.status, Some(StageStatus::Warn));
+        assert_eq!(parsed.issues[0].rule, "below_warn");
+        assert_eq!(parsed.note.as_deref(), Some("70% < 75% warn threshold"));
+
+        let parsed = parse_coverage(&mut output(&llvm_cov_export(59.9, 4), "", 0), &policy());
+        assert_eq!(parsed.status, Some(StageStatus::Fail));
+        assert_eq!(parsed.issues[0].rule, "below_fail");
+
+        // * The policy verdict replaces the plain exit-code mapping on a clean exit.
+        let cfg = tools_config()["cargo-coverage"].clone();
+        let stage = stage_from_result(&cfg, &result("cargo-coverage", 0), &parsed);
+        assert_eq!(stage.status, StageStatus::Fail);
+    }
+
+    #[test]
+    fn coverage_from_tarpaulin_summary() {
+        let stderr = "|| src/lib.rs: 8/10\n78.26% coverage, 18/23 lines covered\n";
+        let mut res = output("", stderr, 0);
+        let parsed = parse_coverage(&mut res, &policy());
+        let coverage = parsed.coverage.unwrap();
+        assert_eq!(coverage.lines_percent, 78.26);
+        assert_eq!(coverage.functions_percent, None);
+        assert_eq!(parsed.status, Some(StageStatus::Ok));
+        assert_eq!(res.stderr, stderr);
+
+        let parsed = parse_coverage(&mut output("", "error: no such command", 101), &policy());
+        assert!(parsed.coverage.is_none());
+        assert!(parsed.status.is_none());
+    }
+
+    /// llvm-cov function record: regions are `[line_start, col, line_end, col, count,
+    /// file_id, expanded_file_id, kind]`.
+    fn cov_function(name: &str, file: &str, regions: &[(u64, u64, u64)]) -> Value {
+        json!({
+            "name": name,
+            "filenames": [file],
+            "regions": regions
+                .iter()
+                .map(|&(start, end, count)| json!([start, 1, end, 2, count, 0, 0, 0]))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    #[test]
+    fn covrank_ranks_uncovered_large_functions_first() {
+        let functions = [
+            // * 10 lines, 8 uncovered.
+            cov_function("big", "src/big.rs", &[(1, 10, 0), (2, 3, 5)]),
+            // * 4 lines, 4 uncovered.
+            cov_function("small", "src/small.rs", &[(1, 4, 0)]),
+            // * Fully covered functions are not targets.
+            cov_function("done", "src/done.rs", &[(1, 20, 3)]),
+            // * Second instantiation of `big` with better coverage wins.
+            cov_function("big", "src/big.rs", &[(1, 10, 1), (9, 10, 0)]),
+            // * Regions from other files (macro expansions) are ignored.
+            json!({ "name": "ext", "filenames": ["src/ext.rs"],
+                    "regions": [[1, 1, 9, 1, 0, 1, 0, 0]] }),
+        ];
+        let ranked = covrank(&functions, 10);
+        let summary: Vec<(&str, u64, u64, u64)> = ranked
+            .iter()
+            .map(|e| {
+                (
+                    e.function.as_str(),
+                    e.size_lines,
+                    e.uncovered_lines,
+                    e.score,
+                )
+            })
+            .collect();
+        assert_eq!(summary, [("small", 4, 4, 16)]);
+
+        let ranked = covrank(&functions[..3], 10);
+        assert_eq!(ranked[0].function, "big");
+        assert_eq!(ranked[0].score, 80);
+        assert_eq!(covrank(&functions[..3], 1).len(), 1);
+    }
+
+    #[test]
+    fn covrank_demangles_symbols() {
+        assert_eq!(
+            demangle_symbol("src/lib.rs:_ZN4demo3add17h0123456789abcdefE"),
+            "demo::add"
+        );
+        assert_eq!(demangle_symbol("plain_name"), "plain_name");
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_not_counted() {
+        let source = "\
+//! Module doc.
+
+/// Item doc.
+fn main() { // trailing comment
+    /* inline */ let x = 1;
+    /* whole line */
+    // let y = 2;
+}
+";
+        assert_eq!(count_executable_lines(source, true), 3);
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        let source = "\
+/* outer
+   /* inner */
+   still in the comment
+*/
+fn f() {}
+/* a /* b */ c */ fn g() {}
+";
+        assert_eq!(count_executable_lines(source, true), 2);
+    }
+
+    #[test]
+    fn comment_markers_inside_strings() {
+        let source = r##"
+fn f() {
+    let url = "http://example.com/*";
+    let raw = r#"// "not" a comment"#;
+    let bytes = br#"/* } */"#;
+    let plain = b"}";
+    let dir = br"C:\";
+}
+fn g() {}
+"##;
+        assert_eq!(count_executable_lines(source, true), 8);
+        assert_eq!(
+            rust_code_lines(r#"let p = br"C:\"; // x"#),
+            [r#"let p = b""; "#]
+        );
+        assert_eq!(rust_code_lines(r#"let p = cr"\";"#), [r#"let p = c"";"#]);
+    }
+
+    #[test]
+    fn char_literals_do_not_open_strings_or_braces() {
+        let source = r#"
+fn f(c: char) -> bool {
+    let quote = '\'';
+    let brace = '{';
+    let escaped = '\u{7d}';
+    let byte = b'"';
+    c == '"'
+}
+#[cfg(test)]
+mod tests {
+    fn t<'a>(s: &'a str) -> &'a str { s }
+}
+"#;
+        assert_eq!(count_executable_lines(source, true), 7);
+        assert_eq!(
+            rust_code_lines(r"let pair = ['\'', '}'];"),
+            ["let pair = [' ', ' '];"]
+        );
+    }
+
+    #[test]
+    fn cfg_test_modules_are_skipped() {
+        let source = "\
+fn f() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t() {
+        f();
+    }
+}
+
+#[cfg(test)]
+fn helper() {}
+";
+        assert_eq!(count_executable_lines(source, true), 3);
+        assert_eq!(count_executable_lines(source, false), 11);
+    }
+
+    #[test]
+    fn cfg_test_module_layouts() {
+        let same_line = "\
+fn f() {}
+#[cfg(test)] mod tests {
+    fn t() {}
+}
+";
+        assert_eq!(count_executable_lines(same_line, true), 1);
+
+        let brace_on_next_line = "\
+fn f() {}
+#[cfg(test)]
+mod tests
+{
+    fn t() {}
+}
+fn g() {}
+";
+        assert_eq!(count_executable_lines(brace_on_next_line, true), 2);
+
+        let attribute_between = "\
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests;
+#[cfg(test)] fn helper() {}
+";
+        assert_eq!(count_executable_lines(attribute_between, true), 4);
+    }
+
+    fn write_config(name: &str, config: &str) -> PathBuf {
+        let path = scratch_dir(name).join("config.json");
+        fs::write(&path, config).unwrap();
+        path
+    }
+
+    fn config_error(name: &str, config: &str) -> String {
+        let path = write_config(name, config);
+        format!("{:#}", load_settings(Some(&path)).unwrap_err())
+    }
+
+    #[test]
+    fn config_overrides_merge_over_defaults() {
+        let path = write_config(
+            "config-merge",
+            r#"{
+                "target_dirs": ["src"],
+                "tools": {
+                    "cargo-test": { "args": ["test", "--workspace"], "critical": false },
+                    "cargo-coverage": { "enabled": false },
+                    "cargo-deny": { "stage": "security", "command": "cargo", "args": ["deny", "check"] }
+                },
+                "stages": {
+                    "lint": { "disabled_rules": ["clippy::needless_return"] },
+                    "coverage": { "warn_threshold": 90, "fail_threshold": 50 },
+                    "line-limits": { "warn_threshold_lines": 100, "fail_threshold_lines": 200 }
+                }
+            }"#,
+        );
+        let settings = load_settings(Some(&path)).unwrap();
+        assert_eq!(settings.target_dirs, ["src"]);
+        assert!(!settings.tools.contains_key("cargo-coverage"));
+
+        let test = &settings.tools["cargo-test"];
+        assert_eq!(test.args, ["test", "--workspace"]);
+        assert!(!test.critical);
+        assert_eq!(test.parser, OutputParser::LibTest);
+        assert!(test
+            .cache_inputs
+            .contains(&path.to_string_lossy().to_string()));
+
+        let deny = &settings.tools["cargo-deny"];
+        assert_eq!((deny.stage.as_str(), deny.critical), ("security", true));
+
+        let clippy = &settings.tools["cargo-clippy"];
+        assert!(clippy
+            .args
+            .ends_with(&strings(&["-A", "clippy::needless_return"])));
+
+        // * CLI flags win over config values, which win over defaults.
+        let cli = Cli::parse_from(["build", "--coverage-fail", "40"]);
+        let policy = settings.policy(&cli, settings.profile(DEFAULT_PROFILE).unwrap());
+        assert_eq!(policy.coverage.warn, 90.0);
+        assert_eq!(policy.coverage.fail, 40.0);
+        assert_eq!(policy.line_limits.warn_lines, 100);
+        assert_eq!(
+            policy.line_limits.max_files_per_dir_warn,
+            LineLimits::default().max_files_per_dir_warn
+        );
+        assert_eq!(policy.disabled_rules["lint"], ["clippy::needless_return"]);
+    }
+
+    #[test]
+    fn config_errors_name_the_offending_key() {
+        let missing = scratch_dir("config-missing").join("absent.json");
+        let err = format!("{:#}", load_settings(Some(&missing)).unwrap_err());
+        assert!(err.contains("Config file not found"), "{err}");
+
+        let err = config_error(
+            "config-unknown",
+            r#"{ "stages": { "lint": { "treshold": 1 } } }"#,
+        );
+        assert!(err.contains("unknown field `treshold`"), "{err}");
+
+        let err = config_error(
+            "config-new-tool",
+            r#"{ "tools": { "x": { "command": "x" } } }"#,
+        );
+        assert!(err.contains("tools.x: new tool requires `stage`"), "{err}");
+
+        let err = config_error(
+            "config-thresholds",
+            r#"{ "target_dirs": [],
+                 "stages": { "coverage": { "warn_threshold": 50, "fail_threshold": 120 } } }"#,
+        );
+        assert!(err.contains("target_dirs: must not be empty"), "{err}");
+        assert!(
+            err.contains("stages.coverage.fail_threshold: must be within 0..=100"),
+            "{err}"
+        );
+        assert!(
+            err.contains("fail_threshold (120) must not exceed warn_threshold (50)"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn profiles_select_stages() {
+        let settings = load_settings(Some(&write_config("profiles-builtin", "{}"))).unwrap();
+        let fast = settings.profile("fast").unwrap();
+        assert!(fast.includes("lint"));
+        assert!(!fast.includes("test"));
+        let full = settings.profile("full").unwrap();
+        assert!(full.includes("coverage"));
+        assert!(!full.includes("archive"));
+        let err = settings.profile("nightly").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Unknown profile: nightly (known: fast, full, maintenance, release)"
+        );
+        assert!(settings.known_stages().contains("line-limits"));
+    }
+
+    #[test]
+    fn config_profiles_replace_builtins() {
+        let path = write_config(
+            "profiles-config",
+            r#"{ "profiles": {
+                "fast": { "stages": ["fmt"] },
+                "nightly": { "skip": ["launch"], "coverage_threshold": 80, "timeout_sec": 600 }
+            } }"#,
+        );
+        let settings = load_settings(Some(&path)).unwrap();
+        let fast = settings.profile("fast").unwrap();
+        assert!(fast.includes("fmt"));
+        assert!(!fast.includes("lint"));
+
+        // * The profile threshold sets `fail` and lifts `warn` to at least the same value.
+        let nightly = settings.profile("nightly").unwrap();
+        let policy = settings.policy(&Cli::parse_from(["build"]), nightly);
+        assert_eq!(policy.coverage.fail, 80.0);
+        assert_eq!(policy.coverage.warn, 80.0);
+
+        let err = config_error(
+            "profiles-invalid",
+            r#"{ "profiles": { "empty": { "stages": [] } } }"#,
+        );
+        assert!(
+            err.contains("profiles.empty.stages: must not be empty"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn layer_reports_in_py_and_ts_shapes() {
+        let py = json!({
+            "ruff-lint": { "exit_code": 1, "stdout": "E501\n", "stderr": "", "available": true },
+            "summary": { "overall_status": "FAIL" }
+        });
+        let mut res = output(&py.to_string(), "", 1);
+        let parsed = parse_legacy_json(&mut res);
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(res.stdout, "E501\n");
+        assert_eq!(parsed.details["layer_tools"], json!(["ruff-lint"]));
+
+        let ts = json!({
+            "tools": {
+                "eslint": { "exitCode": 0, "stdout": "ok\n", "available": true },
+                "tsc": { "exitCode": 0, "available": false, "error": "tsc not found" }
+            },
+            "summary": {}
+        });
+        let mut res = output(&ts.to_string(), "", 0);
+        let parsed = parse_legacy_json(&mut res);
+        assert_eq!(res.exit_code, 0);
+        assert_eq!(res.stderr, "tsc not found");
+        assert_eq!(parsed.note.as_deref(), Some("Not available: tsc"));
+    }
+
+    #[test]
+    fn layer_without_json_report_fails() {
+        let mut res = output("Traceback (most recent call last):", "", 0);
+        let parsed = parse_legacy_json(&mut res);
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(
+            parsed.note.as_deref(),
+            Some("Layer did not print a JSON report")
+        );
+
+        let mut res = output(r#"{"summary": {}}"#, "", 0);
+        let parsed = parse_legacy_json(&mut res);
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(parsed.note.as_deref(), Some("Layer report lists no tools"));
+    }
+
+    #[test]
+    fn layers_expand_into_tools() {
+        let path = write_config(
+            "layers",
+            r#"{ "layers": { "python": {
+                "command": "python",
+                "args": ["tools/ci/build.py", "--json"],
+                "tools": { "ruff-lint": "lint", "pytest": "test" },
+                "critical": false
+            } } }"#,
+        );
+        let settings = load_settings(Some(&path)).unwrap();
+        let tools = settings.layer_tools();
+        assert_eq!(
+            tools.keys().collect::<Vec<_>>(),
+            ["python-pytest", "python-ruff-lint"]
+        );
+        let lint = &tools["python-ruff-lint"];
+        assert_eq!(lint.stage, "lint");
+        assert_eq!(
+            lint.args,
+            ["tools/ci/build.py", "--json", "--tool", "ruff-lint"]
+        );
+        assert_eq!(lint.parser, OutputParser::LegacyJson);
+        assert!(!lint.critical);
+
+        let err = config_error(
+            "layers-clash",
+            r#"{ "layers": { "cargo": { "command": "x", "tools": { "a": "lint" } } } }"#,
+        );
+        assert!(
+            err.contains("layers.cargo: tool names `cargo-*` clash"),
+            "{err}"
+        );
+    }
+
+    fn tool(stage: &str, depends_on: &[&str]) -> ToolConfig {
+        ToolConfig {
+            stage: stage.to_string(),
+            depends_on: strings(depends_on),
+            ..tools_config()["cargo-test"].clone()
+        }
+    }
+
+    #[test]
+    fn stage_order_follows_dependencies() {
+        let mut tools = tools_config();
+        let order = stage_order(&tools).unwrap();
+        assert_eq!(
+            order,
+            [
+                "self-check",
+                "fmt",
+                "line-limits",
+                "lint",
+                "compile",
+                "test",
+                "coverage",
+                "security"
+            ]
+        );
+
+        // * Custom stages may depend on each other and standard stages on them.
+        tools.insert("seed".to_string(), tool("db-seed", &["db-migrate"]));
+        tools.insert("migrate".to_string(), tool("db-migrate", &["compile"]));
+        tools.insert(
+            "e2e".to_string(),
+            tool("e2e", &["db-seed", "no-tool-stage"]),
+        );
+        let order = stage_order(&tools).unwrap();
+        let pos = |stage: &str| order.iter().position(|s| s == stage).unwrap();
+        assert!(pos("compile") < pos("db-migrate"));
+        assert!(pos("db-migrate") < pos("db-seed"));
+        assert!(pos("db-seed") < pos("e2e"));
+        assert!(pos("test") < pos("db-migrate"));
+    }
+
+    #[test]
+    fn stage_order_rejects_cycles() {
+        let mut tools = tools_config();
+        tools.insert("a".to_string(), tool("stage-a", &["stage-b"]));
+        tools.insert("b".to_string(), tool("stage-b", &["stage-a"]));
+        let err = stage_order(&tools).unwrap_err().to_string();
+        assert_eq!(err, "depends_on cycle between stages: stage-a, stage-b");
+
+        let err = config_error(
+            "depends-cycle",
+            r#"{ "tools": {
+                "migrate": { "stage": "db-migrate", "command": "x", "depends_on": ["db-seed"] },
+                "seed": { "stage": "db-seed", "command": "x", "depends_on": ["db-migrate"] },
+                "cargo-check": { "depends_on": ["typo"] }
+            } }"#,
+        );
+        assert!(
+            err.contains("tools: depends_on cycle between stages"),
+            "{err}"
+        );
+        assert!(
+            err.contains("tools.cargo-check.depends_on: unknown stage `typo`"),
+            "{err}"
+        );
+
+        let path = write_config(
+            "depends-custom",
+            r#"{ "tools": {
+                "migrate": { "stage": "db-migrate", "command": "x", "depends_on": ["compile"] },
+                "seed": { "stage": "db-seed", "command": "x", "depends_on": ["db-migrate"] }
+            } }"#,
+        );
+        assert!(load_settings(Some(&path)).is_ok());
+    }
+
+    /// Serializes tests that change the working directory.
+    static CWD_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Runs `f` with `dir` as the working directory (serialized across tests).
+    fn in_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+        let _guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir).unwrap();
+        let out = f();
+        std::env::set_current_dir(previous).unwrap();
+        out
+    }
+
+    /// Runs `run_all_checks` inside `dir` with the given extra CLI args.
+    fn run_in(dir: &Path, args: &[&str]) -> RunOutcome {
+        let cli = Cli::parse_from(["build"].iter().chain(args));
+        in_dir(dir, || run_all_checks(&cli)).unwrap()
+    }
+
+    /// Scratch project whose only tools are `sh -c <script>` commands.
+    fn sh_project(name: &str, tools: Value) -> PathBuf {
+        let dir = scratch_dir(name);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join(".ci")).unwrap();
+        let mut all = serde_json::Map::new();
+        for builtin in tools_config().keys() {
+            all.insert(builtin.clone(), json!({ "enabled": false }));
+        }
+        for (name, tool) in tools.as_object().unwrap() {
+            let mut tool = tool.clone();
+            tool["command"] = json!("sh");
+            tool["args"] = json!(["-c", tool["script"].take()]);
+            tool.as_object_mut().unwrap().remove("script");
+            all.insert(name.clone(), tool);
+        }
+        let config = json!({ "target_dirs": ["src"], "tools": all });
+        fs::write(dir.join(".ci/config.json"), config.to_string()).unwrap();
+        dir
+    }
+
+    fn stage_summary(outcome: &RunOutcome) -> Vec<(String, StageStatus, String)> {
+        outcome
+            .stages
+            .iter()
+            .map(|s| (s.name.clone(), s.status, s.note.clone().unwrap_or_default()))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scheduler_fails_fast_and_skips_dependents() {
+        let dir = sh_project(
+            "fail-fast",
+            json!({
+                "t-security": { "stage": "security", "script": "exit 0" },
+                "t-test": { "stage": "test", "script": "exit 0", "depends_on": ["compile"] },
+                "t-compile": { "stage": "compile", "script": "exit 1" },
+                "t-fmt": { "stage": "fmt", "script": "exit 0" }
+            }),
+        );
+        let ok = String::new();
+        let skip_dep = "Skipped: dependency `compile` did not pass".to_string();
+
+        let outcome = run_in(&dir, &["--no-cache"]);
+        assert_eq!(
+            stage_summary(&outcome),
+            [
+                ("fmt".to_string(), StageStatus::Ok, ok.clone()),
+                (
+                    "compile".to_string(),
+                    StageStatus::Fail,
+                    "Exit code: 1".to_string()
+                ),
+                ("test".to_string(), StageStatus::Skip, skip_dep.clone()),
+                (
+                    "security".to_string(),
+                    StageStatus::Skip,
+                    "Skipped: `compile` failed (fail-fast)".to_string()
+                ),
+            ]
+        );
+
+        let outcome = run_in(&dir, &["--no-cache", "--keep-going"]);
+        let summary = stage_summary(&outcome);
+        assert_eq!(
+            summary[2],
+            ("test".to_string(), StageStatus::Skip, skip_dep)
+        );
+        assert_eq!(summary[3], ("security".to_string(), StageStatus::Ok, ok));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scheduler_serializes_locks_and_waits_for_dependencies() {
+        // * `mkdir` fails if the other holder of the lock is still inside.
+        let guarded = "mkdir held || exit 1; sleep 0.3; rmdir held";
+        let dir = sh_project(
+            "jobs",
+            json!({
+                "t-lint": { "stage": "lint", "script": guarded, "lock": "shared" },
+                "t-compile": { "stage": "compile", "script": guarded, "lock": "shared" },
+                "t-fmt": { "stage": "fmt", "script": "sleep 0.3; touch built" },
+                "t-test": { "stage": "test", "script": "test -f built", "depends_on": ["fmt"] }
+            }),
+        );
+
+        let outcome = run_in(&dir, &["--no-cache", "--jobs", "4"]);
+        let statuses: Vec<_> = outcome
+            .stages
+            .iter()
+            .map(|s| (s.name.as_str(), s.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("fmt", StageStatus::Ok),
+                ("lint", StageStatus::Ok),
+                ("compile", StageStatus::Ok),
+                ("test", StageStatus::Ok),
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cargo_tools_share_the_target_lock() {
+        let tools = tools_config();
+        for name in ["cargo-clippy", "cargo-check", "cargo-test"] {
+            assert_eq!(
+                tools[name].lock.as_deref(),
+                Some(CARGO_TARGET_LOCK),
+                "{name}"
+            );
+        }
+        assert_eq!(tools["cargo-fmt"].lock, None);
+
+        let cli = Cli::parse_from(["build", "--jobs", "3"]);
+        assert_eq!(cli.jobs(), 3);
+        assert!(Cli::parse_from(["build"]).jobs() >= 1);
+    }
+
+    #[test]
+    fn legacy_report_counts_critical_failures() {
+        let (_, legacy) =
+            outcome(vec![result("cargo-fmt", 0), result("cargo-test", 1)]).into_reports();
+        assert_eq!(legacy.summary.total_tools_run, 2);
+        assert_eq!(legacy.summary.critical_failures, 1);
+        assert_eq!(legacy.summary.overall_status, "FAIL");
+        assert!(legacy.tools.contains_key("cargo-fmt"));
+    }
+
+    #[test]
+    fn stats_lines_are_compact_and_second_precision() {
+        let issue = Issue {
+            language: "rust".to_string(),
+            tool: "clippy".to_string(),
+            rule: "clippy::needless_return".to_string(),
+            count: 3,
+            message: Some("unneeded\n   `return` statement".to_string()),
+        };
+        assert_eq!(
+            stats_line(&issue),
+            "rust: [clippy] clippy::needless_return — unneeded `return` statement (x3)"
+        );
+        let bare = Issue {
+            message: None,
+            ..issue
+        };
+        assert_eq!(
+            stats_line(&bare),
+            "rust: [clippy] clippy::needless_return (x3)"
+        );
+
+        assert_eq!(
+            stats_timestamp("2026-10-17T18:46:01.123Z"),
+            "2026-10-17T18:46:01Z"
+        );
+        assert_eq!(stats_timestamp("not a time"), "not a time");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enforcer_logs_rewrite_last_check_and_append_stats() {
+        let dir = sh_project(
+            "enforcer-logs",
+            json!({ "t-fmt": { "stage": "fmt", "script": "exit 0" } }),
+        );
+        let (report, _) = run_in(&dir, &["--no-cache"]).into_reports();
+        in_dir(&dir, || {
+            write_enforcer_logs(&report).unwrap();
+            write_enforcer_logs(&report).unwrap();
+        });
+
+        let last: Value = serde_json::from_str(
+            &fs::read_to_string(dir.join(".enforcer/Enforcer_last_check.log")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(last["status"], "ok");
+        let stats = fs::read_to_string(dir.join(".enforcer/Enforcer_stats.log")).unwrap();
+        assert_eq!(stats.matches("--- Check started at ").count(), 2);
+        assert_eq!(stats.matches("(status=ok) ---").count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn stats_args() -> StatsArgs {
+        StatsArgs {
+            log: ".enforcer/Enforcer_stats.log".into(),
+            since: None,
+            until: None,
+            top: 10,
+            format: StatsFormat::Csv,
+        }
+    }
+
+    fn stats_block(day: u32, status: &str, stages: &str) -> String {
+        format!(
+            "--- Check started at 2026-01-{day:02}T10:00:00Z ---\n\
+             rust: [clippy] needless_return — unneeded `return` (x2)\n\
+             --- Stages: {stages} ---\n\
+             --- Check finished at 2026-01-{day:02}T10:01:00Z (status={status}) ---\n\n"
+        )
+    }
+
+    #[test]
+    fn stats_log_blocks() {
+        let runs = parse_stats_log(&stats_block(1, "warn", "fmt=ok lint=warn"));
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].status.as_deref(), Some("warn"));
+        assert_eq!(runs[0].stages["lint"], "warn");
+        assert_eq!(runs[0].issues["rust: [clippy] needless_return"], 2);
+    }
+
+    #[test]
+    fn fail_rate_is_rounded_only_when_printed() {
+        let mut log = stats_block(1, "fail", "lint=fail test=ok");
+        for day in 2..=6 {
+            log.push_str(&stats_block(day, "ok", "lint=ok test=skip"));
+        }
+        let summary = summarize_stats(&parse_stats_log(&log), &stats_args());
+
+        let lint = summary.stages.iter().find(|s| s.stage == "lint").unwrap();
+        assert_eq!((lint.runs, lint.failed), (6, 1));
+        assert_eq!(lint.fail_rate, 1.0 / 6.0);
+        assert!(stats_csv(&summary).contains("\nstage,lint,6,,,,,1,16.7\n"));
+        assert!(stats_csv(&summary).contains("\nstage,test,1,,,,,0,0.0\n"));
+    }
+
+    fn diff_args() -> DiffArgs {
+        DiffArgs {
+            old: None,
+            new: None,
+            fail_on_regression: false,
+            duration_pct: 20.0,
+            duration_min_ms: 1000,
+            allow_partial: false,
+        }
+    }
+
+    fn saved_report(status: &str, stages: Value, issues: Value, coverage: f64) -> SavedReport {
+        serde_json::from_value(json!({
+            "finished_at_utc": "2026-01-01T10:00:00Z",
+            "status": status,
+            "stages": stages,
+            "issues": issues,
+            "metrics": { "coverage": { "lines_percent": coverage } }
+        }))
+        .unwrap()
+    }
+
+    fn issue_json(rule: &str, count: u64) -> Value {
+        json!({ "language": "rust", "tool": "clippy", "rule": rule, "count": count })
+    }
+
+    #[test]
+    fn diff_lists_issue_stage_coverage_and_duration_changes() {
+        let old = saved_report(
+            "warn",
+            json!([
+                { "name": "fmt", "status": "ok", "duration_ms": 100 },
+                { "name": "lint", "status": "warn", "duration_ms": 10_000 },
+                { "name": "test", "status": "ok", "duration_ms": 5_000 }
+            ]),
+            json!([issue_json("a", 2), issue_json("b", 1), issue_json("c", 4)]),
+            80.0,
+        );
+        let new = saved_report(
+            "fail",
+            json!([
+                { "name": "fmt", "status": "cached", "duration_ms": 0 },
+                { "name": "lint", "status": "warn", "duration_ms": 13_000 },
+                { "name": "test", "status": "fail", "duration_ms": 5_500 }
+            ]),
+            json!([issue_json("a", 3), issue_json("c", 4), issue_json("d", 1)]),
+            78.5,
+        );
+        let diff = diff_reports(&old, &new, &diff_args());
+
+        let names = |list: &[IssueChange]| -> Vec<String> {
+            list.iter().map(|c| c.issue.clone()).collect()
+        };
+        assert_eq!(names(&diff.new_issues), ["rust: [clippy] d"]);
+        assert_eq!(names(&diff.resolved_issues), ["rust: [clippy] b"]);
+        assert_eq!(names(&diff.changed_issues), ["rust: [clippy] a"]);
+        // * `ok` -> `cached` is not a change.
+        let stages: Vec<(&str, bool)> = diff
+            .stage_changes
+            .iter()
+            .map(|c| (c.stage.as_str(), c.regressed))
+            .collect();
+        assert_eq!(stages, [("test", true)]);
+        assert_eq!(diff.coverage_delta, Some(-1.5));
+        let slower: Vec<&str> = diff
+            .duration_regressions
+            .iter()
+            .map(|c| c.stage.as_str())
+            .collect();
+        assert_eq!(slower, ["lint"]);
+        assert!(diff.regressed);
+
+        let same = diff_reports(&new, &new, &diff_args());
+        assert!(!same.regressed);
+        assert!(same.new_issues.is_empty() && same.stage_changes.is_empty());
+    }
+
+    #[test]
+    fn archive_names_never_collide_and_sort_newest_first() {
+        let dir = scratch_dir("history-archive");
+        let names = in_dir(&dir, || {
+            for _ in 0..3 {
+                archive_report("{}", "2026-01-01T10:00:00.123Z", 10).unwrap();
+            }
+            archive_report("{}", "2026-01-01T10:00:01.000Z", 3).unwrap();
+            history_entries()
+                .iter()
+                .map(|p| history_name(p))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            names,
+            [
+                "20260101T100001000Z_nogit",
+                "20260101T100000123Z-3_nogit",
+                "20260101T100000123Z-2_nogit",
+            ]
+        );
+
+        let settings = load_settings(Some(&write_config(
+            "history-max",
+            r#"{ "history": { "max_count": 0 } }"#,
+        )))
+        .unwrap();
+        assert_eq!(settings.history_max, 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn resolve_report_tries_index_then_commit_then_timestamp() {
+        let dir = scratch_dir("history-resolve");
+        let history = dir.join(HISTORY_DIR);
+        fs::create_dir_all(&history).unwrap();
+        for name in [
+            "20260101T100000000Z_1a2b3c4",
+            "20260102T100000000Z_2026abc",
+            "20260103T100000000Z_9f8e7d6",
+        ] {
+            fs::write(history.join(format!("{name}.json")), "{}").unwrap();
+        }
+        let resolved = |arg: &str| {
+            in_dir(&dir, || resolve_report(arg))
+                .map(|p| history_name(&p))
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(resolved("0").unwrap(), "20260103T100000000Z_9f8e7d6");
+        assert_eq!(resolved("#2").unwrap(), "20260101T100000000Z_1a2b3c4");
+        assert!(resolved("#7")
+            .unwrap_err()
+            .contains("History entry 7 not found"));
+        // * A commit prefix wins over a timestamp prefix.
+        assert_eq!(resolved("2026").unwrap(), "20260102T100000000Z_2026abc");
+        assert_eq!(resolved("1a2b").unwrap(), "20260101T100000000Z_1a2b3c4");
+        assert_eq!(resolved("20260103").unwrap(), "20260103T100000000Z_9f8e7d6");
+        assert!(resolved("202601")
+            .unwrap_err()
+            .contains("matches 3 history entries"));
+        assert!(resolved("zzz").unwrap_err().contains("No report file"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cvss3_known_vectors() {
+        let score = |v: &str| cvss3_base_score(v);
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H"),
+            Some(9.8)
+        );
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:H/A:N"),
+            Some(7.5)
+        );
+        assert_eq!(
+            score("CVSS:3.0/AV:L/AC:L/PR:L/UI:N/S:U/C:H/I:H/A:H"),
+            Some(7.8)
+        );
+        // Scope changed.
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:C/C:H/I:H/A:H"),
+            Some(10.0)
+        );
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:N/UI:R/S:C/C:L/I:L/A:N"),
+            Some(6.1)
+        );
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:L/UI:R/S:C/C:L/I:L/A:N"),
+            Some(5.4)
+        );
+        assert_eq!(
+            score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:N/A:N"),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn cvss3_rejects_other_versions_and_malformed_vectors() {
+        assert_eq!(cvss3_base_score("AV:N/AC:L/Au:N/C:P/I:P/A:P"), None);
+        assert_eq!(cvss3_base_score("CVSS:4.0/AV:N/AC:L/AT:N/PR:N/UI:N"), None);
+        assert_eq!(
+            cvss3_base_score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/C:H/I:H/A:H"),
+            None
+        );
+        assert_eq!(
+            cvss3_base_score("CVSS:3.1/AV:X/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H"),
+            None
+        );
+    }
+
+    #[test]
+    fn severity_bands() {
+        assert_eq!(severity_from_score(9.8), Severity::Critical);
+        assert_eq!(severity_from_score(7.0), Severity::High);
+        assert_eq!(severity_from_score(6.9), Severity::Medium);
+        assert_eq!(severity_from_score(0.1), Severity::Low);
+        assert_eq!(severity_from_score(0.0), Severity::None);
+    }
+
+    fn security_policy(fail_severity: Severity, ignore: &[(&str, &str)]) -> SecurityPolicy {
+        SecurityPolicy {
+            fail_severity,
+            ignore: ignore
+                .iter()
+                .map(|(id, reason)| (id.to_string(), reason.to_string()))
+                .collect(),
+        }
+    }
+
+    const AUDIT_JSON: &str = r#"{
+  "database": {"advisory-count": 600},
+  "lockfile": {"dependency-count": 120},
+  "vulnerabilities": {"found": true, "count": 2, "list": [
+    {"advisory": {"id": "RUSTSEC-2021-0078", "package": "hyper",
+                  "title": "Lenient `hyper` header parsing of `Content-Length`",
+                  "cvss": "CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:H/A:N",
+                  "aliases": ["CVE-2021-32715"]},
+     "package": {"name": "hyper", "version": "0.14.9"}},
+    {"advisory": {"id": "RUSTSEC-2020-0071", "package": "time",
+                  "title": "Potential segfault in the time crate",
+                  "cvss": "CVSS:3.1/AV:L/AC:H/PR:N/UI:N/S:U/C:N/I:N/A:H",
+                  "aliases": ["CVE-2020-26235"]},
+     "package": {"name": "time", "version": "0.1.43"}}
+  ]},
+  "warnings": {
+    "unmaintained": [
+      {"kind": "unmaintained", "package": {"name": "ansi_term", "version": "0.12.1"},
+       "advisory": {"id": "RUSTSEC-2021-0139", "package": "ansi_term",
+                    "title": "ansi_term is Unmaintained", "cvss": null}}
+    ],
+    "yanked": [
+      {"kind": "yanked", "package": {"name": "foo", "version": "1.0.0"}, "advisory": null}
+    ]
+  }
+}"#;
+
+    #[test]
+    fn cargo_audit_report() {
+        let mut res = output(AUDIT_JSON, "", 1);
+        let parsed = parse_advisories(&mut res, "audit", &security_policy(Severity::High, &[]));
+
+        assert_eq!(parsed.status, Some(StageStatus::Fail));
+        assert_eq!(
+            parsed.note.as_deref(),
+            Some("2 vulnerabilities (1 failing), 2 warnings")
+        );
+        let rules: Vec<&str> = parsed.issues.iter().map(|i| i.rule.as_str()).collect();
+        assert_eq!(
+            rules,
+            [
+                "RUSTSEC-2021-0078",
+                "RUSTSEC-2020-0071",
+                "RUSTSEC-2021-0139",
+                "yanked"
+            ]
+        );
+        assert!(parsed.issues.iter().all(|i| i.tool == "audit"));
+        assert_eq!(parsed.details["advisories"][0]["severity"], json!("high"));
+        assert_eq!(parsed.details["advisories"][1]["severity"], json!("medium"));
+        assert_eq!(
+            parsed.details["advisories"][3]["title"],
+            json!("yanked crate")
+        );
+        assert_eq!(res.exit_code, 1);
+        assert!(res
+            .stdout
+            .starts_with("RUSTSEC-2021-0078 hyper@0.14.9 [vulnerability, high] Lenient"));
+    }
+
+    #[test]
+    fn advisory_verdict_ignores_the_exit_code() {
+        let cfg = tools_config()["cargo-audit"].clone();
+        let policy = security_policy(Severity::Critical, &[]);
+
+        // * cargo-audit exits 1 for any vulnerability; none reaches `critical`.
+        let mut res = output(AUDIT_JSON, "", 1);
+        let parsed = parse_advisories(&mut res, "audit", &policy);
+        let stage = stage_from_result(&cfg, &res, &parsed);
+        assert_eq!(stage.status, StageStatus::Warn);
+        assert_eq!(stage.details.unwrap()["exit_code"], 1);
+
+        let mut res = output(r#"{"vulnerabilities": {"list": []}}"#, "", 0);
+        let parsed = parse_advisories(&mut res, "audit", &policy);
+        assert_eq!(
+            stage_from_result(&cfg, &res, &parsed).status,
+            StageStatus::Ok
+        );
+
+        // * The label follows the configured tool, not the command line text.
+        let mut deny = cfg.clone();
+        deny.args = strings(&["deny", "--format", "json", "check", "advisories"]);
+        assert_eq!(advisory_tool(&cfg), "audit");
+        assert_eq!(advisory_tool(&deny), "deny");
+    }
+
+    #[test]
+    fn ignored_advisories_match_aliases() {
+        let mut res = output(AUDIT_JSON, "", 1);
+        let parsed = parse_advisories(
+            &mut res,
+            "audit",
+            &security_policy(
+                Severity::High,
+                &[
+                    ("CVE-2021-32715", "not reachable"),
+                    ("RUSTSEC-0000-0000", "old"),
+                ],
+            ),
+        );
+
+        assert_eq!(parsed.status, Some(StageStatus::Warn));
+        assert_eq!(
+            parsed.note.as_deref(),
+            Some("1 vulnerabilities (0 failing), 2 warnings, 1 ignored")
+        );
+        assert_eq!(
+            parsed.details["ignored"],
+            json!([{ "id": "RUSTSEC-2021-0078", "package": "hyper", "reason": "not reachable" }])
+        );
+        assert_eq!(
+            parsed.details["unused_ignores"],
+            json!(["RUSTSEC-0000-0000"])
+        );
+    }
+
+    #[test]
+    fn cargo_deny_json_lines() {
+        let stderr = r#"    Fetching advisory database
+{"type":"diagnostic","fields":{"severity":"error","code":"vulnerability","message":"Lenient hyper header parsing","advisory":{"id":"RUSTSEC-2021-0078","package":"hyper","title":"Lenient `hyper` header parsing of `Content-Length`","cvss":"CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:H/A:N","aliases":["CVE-2021-32715"]},"graphs":[{"Krate":{"name":"hyper","version":"0.14.9"},"parents":[]}]}}
+{"type":"diagnostic","fields":{"severity":"warning","code":"unmaintained","message":"ansi_term is Unmaintained","advisory":{"id":"RUSTSEC-2021-0139","package":"ansi_term","title":"ansi_term is Unmaintained"},"graphs":[{"Krate":{"name":"ansi_term","version":"0.12.1"}}]}}
+{"type":"summary","fields":{"advisories":{"errors":1,"warnings":1}}}
+"#;
+        let mut res = output("", stderr, 1);
+        let parsed = parse_advisories(&mut res, "deny", &security_policy(Severity::Critical, &[]));
+
+        assert_eq!(parsed.status, Some(StageStatus::Warn));
+        assert_eq!(
+            parsed.note.as_deref(),
+            Some("1 vulnerabilities (0 failing), 1 warnings")
+        );
+        assert!(parsed.issues.iter().all(|i| i.tool == "deny"));
+        assert_eq!(
+            parsed.details["advisories"][1],
+            json!({
+                "id": "RUSTSEC-2021-0139",
+                "package": "ansi_term",
+                "version": "0.12.1",
+                "kind": "unmaintained",
+                "title": "ansi_term is Unmaintained"
+            })
+        );
+    }
+
+    #[test]
+    fn advisory_output_without_json_is_left_alone() {
+        let mut res = output("error: failed to fetch", "", 1);
+        let parsed = parse_advisories(&mut res, "audit", &security_policy(Severity::High, &[]));
+        assert_eq!(parsed.status, None);
+        assert!(parsed.issues.is_empty());
+        assert_eq!(res.exit_code, 1);
+    }
+
+    #[test]
+    fn changed_since_reports_edits_additions_and_restores() {
+        let snapshot = |entries: &[(&str, &str)]| -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(path, hash)| (path.to_string(), hash.to_string()))
+                .collect()
+        };
+        let before = snapshot(&[("a.rs", "1"), ("b.rs", "2"), ("dirty.rs", "3")]);
+        let after = snapshot(&[("a.rs", "1"), ("b.rs", "9"), ("new.rs", "4")]);
+        assert_eq!(
+            changed_since(&before, &after),
+            ["b.rs", "dirty.rs", "new.rs"]
+        );
+        assert!(changed_since(&after, &after).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fix_lists_changed_files_and_rechecks() {
+        let dir = sh_project(
+            "fix",
+            json!({
+                "t-fmt": {
+                    "stage": "fmt",
+                    "script": "grep -q fixed src/lib.rs",
+                    "can_fix": true,
+                    "args_fix": ["-c", "echo fixed > src/lib.rs"]
+                },
+                "t-lint": {
+                    "stage": "lint",
+                    "script": "exit 0",
+                    "can_fix": true,
+                    "args_fix": ["-c", "exit 3"]
+                }
+            }),
+        );
+        fs::write(dir.join("src/lib.rs"), "broken\n").unwrap();
+
+        let outcome = run_in(&dir, &["--fix"]);
+        let fmt = &outcome.stages[0];
+        assert_eq!(fmt.status, StageStatus::Ok);
+        let details = fmt.details.as_ref().unwrap();
+        assert_eq!(details["fixed_files"], json!(["src/lib.rs"]));
+        assert_eq!(details["fixed"], true);
+        assert_eq!(fmt.note.as_deref(), Some("fixed 1 file(s)"));
+
+        // * A fix that changed nothing is not `fixed`, whatever its exit code.
+        let lint = outcome.stages[1].details.as_ref().unwrap();
+        assert_eq!(lint["fixed"], false);
+        assert_eq!(lint["fix"]["exit_code"], 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn schema_errors(value: Value, schema: Value) -> Vec<String> {
+        let mut errors = Vec::new();
+        validate_json(&value, &schema, &schema, "", &mut errors);
+        errors
+    }
+
+    #[test]
+    fn schema_type_mismatch_stops_at_the_value() {
+        let schema = json!({ "type": "integer", "minimum": 1 });
+        assert_eq!(
+            schema_errors(json!("3"), schema.clone()),
+            ["(root): expected integer"]
+        );
+        assert_eq!(
+            schema_errors(json!(1.5), schema.clone()),
+            ["(root): expected integer"]
+        );
+        assert!(schema_errors(json!(3), schema).is_empty());
+        let nullable = json!({ "type": ["string", "null"] });
+        assert!(schema_errors(json!(null), nullable.clone()).is_empty());
+        assert_eq!(
+            schema_errors(json!(true), nullable),
+            ["(root): expected string or null"]
+        );
+    }
+
+    #[test]
+    fn schema_required_enum_and_range() {
+        let required = json!({ "type": "object", "required": ["command", "args"] });
+        assert_eq!(
+            schema_errors(json!({ "command": "cargo" }), required.clone()),
+            ["(root): missing `args`"]
+        );
+        assert!(schema_errors(json!({ "command": "cargo", "args": [] }), required).is_empty());
+
+        let channel = json!({ "enum": ["stable", "beta", "nightly"] });
+        assert!(schema_errors(json!("beta"), channel.clone()).is_empty());
+        assert_eq!(
+            schema_errors(json!("dev"), channel),
+            [r#"(root): must be one of "stable", "beta", "nightly""#]
+        );
+
+        let percent = json!({ "type": "number", "minimum": 0, "maximum": 100 });
+        assert!(schema_errors(json!(0), percent.clone()).is_empty());
+        assert!(schema_errors(json!(100), percent.clone()).is_empty());
+        assert_eq!(
+            schema_errors(json!(-1), percent.clone()),
+            ["(root): -1 is out of range"]
+        );
+        assert_eq!(
+            schema_errors(json!(100.5), percent),
+            ["(root): 100.5 is out of range"]
+        );
+    }
+
+    #[test]
+    fn schema_additional_properties() {
+        let closed = json!({
+            "type": "object",
+            "properties": { "tools": { "type": "object" } },
+            "additionalProperties": false
+        });
+        assert_eq!(
+            schema_errors(json!({ "tool": {} }), closed),
+            ["tool: unknown key"]
+        );
+
+        let open_typed = json!({
+            "type": "object",
+            "additionalProperties": { "type": "boolean" }
+        });
+        assert!(schema_errors(json!({ "a": true }), open_typed.clone()).is_empty());
+        assert_eq!(
+            schema_errors(json!({ "a": 1 }), open_typed),
+            ["a: expected boolean"]
+        );
+        assert!(schema_errors(json!({ "anything": 1 }), json!({ "type": "object" })).is_empty());
+    }
+
+    #[test]
+    fn schema_nested_paths_refs_and_empty_values() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "tools": {
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/definitions/tool" }
+                },
+                "name": { "type": "string", "minLength": 1 },
+                "dirs": { "type": "array", "minItems": 1 },
+                "stages": { "type": "object", "minProperties": 1 }
+            },
+            "definitions": {
+                "tool": {
+                    "type": "object",
+                    "properties": {
+                        "args": { "type": "array", "items": { "type": "string" } },
+                        "lock": { "$ref": "#/definitions/missing" }
+                    }
+                }
+            }
+        });
+        let config = json!({
+            "tools": { "fmt": { "args": ["a", 1], "lock": "x" } },
+            "name": "",
+            "dirs": [],
+            "stages": {}
+        });
+        assert_eq!(
+            schema_errors(config, schema),
+            [
+                "dirs: must not be empty",
+                "name: must not be empty",
+                "stages: must not be empty",
+                "tools.fmt.args[1]: expected string",
+                "tools.fmt.lock: unresolved schema $ref `#/definitions/missing`"
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_config_fails_self_check_and_blocks_the_run() {
+        let dir = scratch_dir("self-check-config");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join(".ci")).unwrap();
+        fs::write(
+            dir.join(".ci/config.json"),
+            "{ \"target_dirs\": [\"src\"], }",
+        )
+        .unwrap();
+
+        let outcome = run_in(&dir, &["--no-cache"]);
+        let check = &outcome.stages[0];
+        assert_eq!(check.name, SELF_CHECK_STAGE);
+        assert_eq!(check.status, StageStatus::Fail);
+        assert!(outcome.issues.iter().any(|i| i.rule == "config-parse"));
+        assert!(outcome.stages[1..]
+            .iter()
+            .all(|s| s.status == StageStatus::Skip));
+
+        // * Without `self-check` in the run, a broken config still aborts.
+        let cli = Cli::parse_from(["build", "--skip", "self-check"]);
+        assert!(in_dir(&dir, || run_all_checks(&cli)).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn parse_version_from_version_lines() {
+        let v = |text: &str| parse_version(text);
+        assert_eq!(
+            v("cargo 1.79.0 (ffa9cf99a 2024-06-03)"),
+            Some(vec![1, 79, 0])
+        );
+        assert_eq!(v("cargo-llvm-cov 0.6.10"), Some(vec![0, 6, 10]));
+        assert_eq!(v("v20.11.0"), Some(vec![20, 11, 0]));
+        // * Missing patch (and minor) parts stay short; comparison pads them with zeros.
+        assert_eq!(v("0.6"), Some(vec![0, 6]));
+        // * Pre-release and build metadata are dropped: `1.80.0-nightly` counts as 1.80.0.
+        assert_eq!(
+            v("rustc 1.80.0-nightly (032af18af 2024-06-02)"),
+            Some(vec![1, 80, 0])
+        );
+        assert_eq!(v("cargo-nextest 0.9.72+build.5"), Some(vec![0, 9, 72]));
+        assert_eq!(v("1.2.x"), None);
+        assert_eq!(v("unknown"), None);
+    }
+
+    #[test]
+    fn version_below_compares_numerically_with_padding() {
+        let below = |found: &str, min: &str| {
+            version_below(&parse_version(found).unwrap(), &parse_version(min).unwrap())
+        };
+        assert!(below("0.6.9", "0.6.10"));
+        assert!(!below("0.6.10", "0.6.9"));
+        assert!(!below("1.79", "1.79.0"));
+        assert!(below("1.79.0", "1.79.1"));
+        assert!(!below("2", "1.99"));
+        assert!(!below("1.80.0-nightly", "1.80"));
+    }
+
+    #[test]
+    fn probes_follow_the_toolchain_override() {
+        let args = strings;
+        assert_eq!(
+            probe_name("cargo", &args(&["llvm-cov", "--json"])),
+            "cargo-llvm-cov"
+        );
+        assert_eq!(
+            probe_name("cargo", &args(&["test", "--workspace"])),
+            "cargo"
+        );
+        assert_eq!(
+            probe_name("cargo", &args(&["+nightly", "fmt"])),
+            "cargo-fmt"
+        );
+        assert_eq!(probe_name("python3", &args(&["build.py"])), "python3");
+        assert_eq!(
+            toolchain_override("cargo", &args(&["+nightly", "fmt"])),
+            Some("nightly")
+        );
+        assert_eq!(toolchain_override("cargo", &args(&["fmt"])), None);
+        assert_eq!(toolchain_override("rustup", &args(&["+nightly"])), None);
+        assert_eq!(
+            probe_key("cargo-fmt", Some("nightly")),
+            "cargo-fmt +nightly"
+        );
+        assert_eq!(probe_key("cargo", None), "cargo");
+        assert_eq!(
+            install_hint("cargo-audit"),
+            "cargo install cargo-audit --locked"
+        );
+        assert_eq!(install_hint("cargo-clippy"), "rustup component add clippy");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preflight_blocks_missing_and_unversioned_tools() {
+        let dir = sh_project(
+            "preflight",
+            json!({
+                "t-fmt": { "stage": "fmt", "script": "true", "critical": false },
+                "t-lint": { "stage": "lint", "script": "true", "critical": false },
+                "t-test": { "stage": "test", "script": "true" }
+            }),
+        );
+        let path = dir.join(".ci/config.json");
+        let mut config: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        for tool in ["t-lint", "t-test"] {
+            config["tools"][tool]["command"] = json!("ci-no-such-tool");
+        }
+        // * `sh --version` prints no version, so a minimum cannot be met.
+        config["toolchain"] = json!({ "min_versions": { "sh": "1.0" } });
+        fs::write(&path, config.to_string()).unwrap();
+
+        let summary = stage_summary(&run_in(&dir, &["--no-cache", "--keep-going"]));
+        assert_eq!(summary[0].1, StageStatus::Warn);
+        assert!(summary[0].2.contains("(unknown version)"), "{summary:?}");
+        assert_eq!(summary[1].1, StageStatus::Skip);
+        assert!(summary[1].2.contains("`ci-no-such-tool` is not available"));
+        assert_eq!(summary[2].1, StageStatus::Fail);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn with_packages_inserts_before_separator() {
+        let packages = strings(&["a", "b"]);
+        assert_eq!(
+            with_packages(&strings(&["fmt", "--all", "--", "--check"]), &packages),
+            strings(&["fmt", "-p", "a", "-p", "b", "--", "--check"])
+        );
+        assert_eq!(
+            with_packages(&strings(&["test", "--workspace"]), &packages[1..]),
+            strings(&["test", "-p", "b"])
+        );
+        // * Arguments after `--` belong to the inner tool and are left alone.
+        assert_eq!(
+            with_packages(&strings(&["test", "--", "--all"]), &packages[..1]),
+            strings(&["test", "-p", "a", "--", "--all"])
+        );
+        assert!(with_packages(&[], &packages).is_empty());
+    }
+
+    #[test]
+    fn paths_select_the_innermost_member_or_every_member_below() {
+        let dir = scratch_dir("members");
+        let root = fs::canonicalize(&dir).unwrap();
+        let member = |name: &str, rel: &str| WorkspaceMember {
+            name: name.to_string(),
+            dir: root.join(rel),
+            edition: "2021".to_string(),
+            deps: BTreeSet::new(),
+        };
+        let workspace = Workspace {
+            root: root.clone(),
+            members: vec![
+                member("root", ""),
+                member("a", "crates/a"),
+                member("b", "crates/b"),
+            ],
+        };
+        let names = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        in_dir(&dir, || {
+            let select = |paths: &[&str]| workspace.members_for_paths(&strings(paths));
+            assert_eq!(select(&["crates/a/src/lib.rs"]), names(&["a"]));
+            assert_eq!(select(&["crates/b/../a/gone.rs"]), names(&["a"]));
+            assert_eq!(select(&["crates"]), names(&["a", "b"]));
+            assert_eq!(select(&["src/main.rs"]), names(&["root"]));
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn path_runs_narrow_cargo_tools_and_their_cache_keys() {
+        let cli = Cli::parse_from(["build"]);
+        let settings = default_settings();
+        let profile = settings.profile(DEFAULT_PROFILE).unwrap();
+        let mut ctx = RunContext {
+            cli: &cli,
+            settings: &settings,
+            profile,
+            policy: settings.policy(&cli, profile),
+            hb: cli.heartbeat(),
+            target_paths: Vec::new(),
+            tool_versions: BTreeMap::new(),
+            packages: Vec::new(),
+            scope: None,
+            crate_files: None,
+        };
+        let tools = &settings.tools;
+        assert!(package_scoped(&tools["cargo-clippy"]));
+        assert!(!package_scoped(&tools["cargo-coverage"]));
+        assert!(packages_config(&ctx, &tools["cargo-clippy"]).is_none());
+
+        ctx.packages = strings(&["a", "b"]);
+        // * Coverage runs once over the selected crates, its fallback as well.
+        let coverage = packages_config(&ctx, &tools["cargo-coverage"]).unwrap();
+        assert_eq!(
+            coverage.args,
+            strings(&["llvm-cov", "--all-features", "--json", "-p", "a", "-p", "b"])
+        );
+        assert_eq!(
+            coverage.fallback_args,
+            strings(&["tarpaulin", "--all-features", "-p", "a", "-p", "b"])
+        );
+        assert!(packages_config(&ctx, &tools["line-limits"]).is_none());
+        assert!(packages_config(&ctx, &tools["cargo-audit"]).is_none());
+    }
+
+    #[test]
+    fn partial_runs_are_not_in_fail_rates() {
+        let mut log = stats_block(1, "ok", "lint=ok");
+        log.push_str(&stats_block(2, "fail", "lint=fail").replacen(
+            " ---\n",
+            " ---\n--- Scope: partial, 1 file(s) staged; crates: a ---\n",
+            1,
+        ));
+        let runs = parse_stats_log(&log);
+        assert!(!runs[0].partial && runs[1].partial);
+
+        let summary = summarize_stats(&runs, &stats_args());
+        assert_eq!(
+            (summary.runs, summary.failed_runs, summary.partial_runs),
+            (2, 0, 1)
+        );
+        assert_eq!(summary.stages[0].runs, 1);
+        assert_eq!(summary.stages[0].fail_rate, 0.0);
+        assert_eq!(summary.top_rules[0].runs, 2);
+    }
+
+    #[test]
+    fn diff_refuses_partial_reports_unless_allowed() {
+        let dir = scratch_dir("diff-partial");
+        let full = json!({
+            "finished_at_utc": "2026-01-01T10:00:00Z",
+            "status": "fail",
+            "stages": [{ "name": "lint", "status": "fail", "duration_ms": 10 }],
+            "issues": [issue_json("a", 1)]
+        });
+        let mut partial = full.clone();
+        partial["status"] = json!("ok");
+        partial["stages"] = json!([]);
+        partial["issues"] = json!([]);
+        partial["scope"] = json!({ "mode": "staged", "files": [], "crates": [] });
+        let (old, new) = (dir.join("old.json"), dir.join("new.json"));
+        fs::write(&old, full.to_string()).unwrap();
+        fs::write(&new, partial.to_string()).unwrap();
+
+        let diff = diff_reports(
+            &SavedReport::load(&old).unwrap(),
+            &SavedReport::load(&new).unwrap(),
+            &diff_args(),
+        );
+        assert_eq!(diff.partial, ["new"]);
+        // * The skipped lint stage would otherwise read as a fix.
+        assert_eq!(diff.resolved_issues.len(), 1);
+
+        let mut args = diff_args();
+        args.old = Some(old.to_string_lossy().to_string());
+        args.new = Some(new.to_string_lossy().to_string());
+        let err = run_diff(&args, true).unwrap_err().to_string();
+        assert!(err.contains("new report is from a partial run"), "{err}");
+        args.allow_partial = true;
+        assert!(run_diff(&args, true).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn ws_member(name: &str, deps: &[&str]) -> WorkspaceMember {
+        WorkspaceMember {
+            name: name.to_string(),
+            dir: PathBuf::from(format!("/ws/crates/{name}")),
+            edition: "2021".to_string(),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn dependents_are_transitive() {
+        let workspace = Workspace {
+            root: PathBuf::from("/ws"),
+            members: vec![
+                ws_member("a", &[]),
+                ws_member("b", &["a"]),
+                ws_member("c", &["b"]),
+                ws_member("d", &[]),
+            ],
+        };
+        let selected = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        assert_eq!(
+            workspace.with_dependents(selected(&["a"])),
+            selected(&["a", "b", "c"])
+        );
+        assert_eq!(
+            workspace.with_dependents(selected(&["d"])),
+            selected(&["d"])
+        );
+        assert_eq!(
+            workspace
+                .member_of(Path::new("/ws/crates/b/src/lib.rs"))
+                .map(|m| m.name.as_str()),
+            Some("b")
+        );
+        assert!(workspace.is_workspace_wide(Path::new("/ws/Cargo.lock")));
+        assert!(!workspace.is_workspace_wide(Path::new("/ws/crates/a/Cargo.toml")));
+    }
+
+    #[test]
+    fn crate_runs_per_package_and_per_file() {
+        let cli = Cli::parse_from(["build"]);
+        let settings = default_settings();
+        let profile = settings.profile(DEFAULT_PROFILE).unwrap();
+        let mut ctx = RunContext {
+            cli: &cli,
+            settings: &settings,
+            profile,
+            policy: settings.policy(&cli, profile),
+            hb: cli.heartbeat(),
+            target_paths: Vec::new(),
+            tool_versions: BTreeMap::new(),
+            packages: Vec::new(),
+            scope: None,
+            crate_files: None,
+        };
+        let fmt = &settings.tools["cargo-fmt"];
+        let clippy = &settings.tools["cargo-clippy"];
+        // * No packages selected: the tool runs once, workspace-wide.
+        assert!(crate_runs(&ctx, clippy).is_empty());
+
+        ctx.packages = strings(&["a", "b"]);
+        let runs = crate_runs(&ctx, clippy);
+        let names: Vec<&str> = runs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert!(runs[1].1.args.windows(3).any(|w| w == ["-p", "b", "--"]));
+
+        // * A file-scoped run formats just the changed files of each crate.
+        ctx.crate_files = Some(vec![(
+            ws_member("a", &[]),
+            strings(&["crates/a/src/lib.rs"]),
+        )]);
+        let runs = crate_runs(&ctx, fmt);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].1.command, "rustfmt");
+        assert_eq!(
+            runs[0].1.args,
+            strings(&["--edition", "2021", "--check", "crates/a/src/lib.rs"])
+        );
+        assert_eq!(crate_runs(&ctx, clippy).len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn staged_run_records_its_scope_and_skips_coverage() {
+        let dir = sh_project(
+            "staged",
+            json!({
+                "t-lint": { "stage": "lint", "script": "true" },
+                "t-cov": { "stage": "coverage", "script": "true", "parser": "coverage" }
+            }),
+        );
+        fs::write(dir.join("src/a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.join("src/b.rs"), "fn b() {}\n").unwrap();
+        let git = |args: &[&str]| {
+            let ok = Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+                .status
+                .success();
+            assert!(ok, "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        git(&["add", "src/a.rs"]);
+
+        let outcome = run_in(&dir, &["--staged"]);
+        let scope = outcome.scope.as_ref().unwrap();
+        assert_eq!(scope.mode, "staged");
+        assert_eq!(scope.files, ["src/a.rs"]);
+        let summary = stage_summary(&outcome);
+        assert_eq!(summary[0].1, StageStatus::Ok);
+        assert_eq!(summary[1].1, StageStatus::Skip);
+        assert!(summary[1].2.contains("not measured in partial runs"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn discovery_anchors_exclusions_to_the_project_and_crate_roots() {
+        let dir = scratch_dir("discover");
+        for (file, text) in [
+            ("src/target/mod.rs", ""),
+            ("target/debug/out.rs", ""),
+            ("crates/a/Cargo.toml", "[package]"),
+            ("crates/a/src/lib.rs", ""),
+            ("crates/a/target/out.rs", ""),
+            ("docs/.ci_cache/notes.md", ""),
+            (".ci_cache/report.json", "{}"),
+            (".enforcer/Enforcer_stats.log", ""),
+            ("vendor/sub/lib.rs", ""),
+            (".gitmodules", "[submodule \"sub\"]\n\tpath = vendor/sub\n"),
+            ("build.log", ""),
+            (".gitignore", "*.log\n"),
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        let listed = |untracked: bool| {
+            in_dir(&dir, || discover_files(&strings(&["."]), untracked))
+                .unwrap()
+                .iter()
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .collect::<Vec<_>>()
+        };
+        let sources = [
+            "crates/a/Cargo.toml",
+            "crates/a/src/lib.rs",
+            "docs/.ci_cache/notes.md",
+            "src/target/mod.rs",
+        ];
+
+        // * Outside git the walker applies the same exclusions (ignore files aside).
+        let walked = listed(true);
+        assert!(walked.iter().any(|f| f == "build.log"));
+        let walked: Vec<_> = walked
+            .into_iter()
+            .filter(|f| !f.starts_with(".git") && f != "build.log")
+            .collect();
+        assert_eq!(walked, sources);
+
+        let git = |args: &[&str]| {
+            let ok = Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+                .status
+                .success();
+            assert!(ok, "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        let tracked: Vec<_> = listed(true)
+            .into_iter()
+            .filter(|f| !f.starts_with(".git"))
+            .collect();
+        assert_eq!(tracked, sources);
+        assert!(listed(false).is_empty());
+        git(&["add", "src"]);
+        assert_eq!(listed(false), ["src/target/mod.rs"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}